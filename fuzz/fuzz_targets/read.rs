@@ -0,0 +1,26 @@
+//! Arbitrary bytes through the readers: they may fail, but never panic.
+//!
+//! Seeded with the framing corpus of the unit tests:
+//! `cargo fuzz run read fuzz/corpus/read tests/corpus/framing`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lsp_copilot::msg::{Message, ReadOptions, ReadOutcome};
+
+fuzz_target!(|data: &[u8]| {
+    for accept_bytecode in [false, true] {
+        let opts = ReadOptions {
+            accept_bytecode,
+            ..Default::default()
+        };
+        let mut input = data;
+        while let Ok(Some(_)) = Message::read_with_options(&mut input, &opts) {}
+        let mut input = data;
+        while let Ok(Some(_)) = Message::read_batch_with_options(&mut input, &opts) {}
+    }
+    let mut input = data;
+    while let Ok(ReadOutcome::Message(_) | ReadOutcome::Skipped { .. }) =
+        Message::read_recoverable(&mut input)
+    {}
+});
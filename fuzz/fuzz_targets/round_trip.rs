@@ -0,0 +1,43 @@
+//! Every message read from arbitrary bytes is written and read back
+//! unchanged, as JSON and as bytecode.
+//!
+//! `cargo fuzz run round_trip fuzz/corpus/round_trip tests/corpus/framing`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lsp_copilot::{
+    bytecode::BytecodeOptions,
+    msg::{Message, ReadOptions, WriteOptions},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = data;
+    let Ok(Some(msg)) = Message::read(&mut input) else {
+        return;
+    };
+    let expected = serde_json::to_value(&msg).unwrap();
+
+    // JSON bodies are preserved as they are
+    let json = WriteOptions {
+        bytecode: BytecodeOptions {
+            min_bytecode_bytes: Some(usize::MAX),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    msg.clone().write_with_options(&mut out, &json).unwrap();
+    let read = Message::read(&mut out.as_slice()).unwrap().unwrap();
+    assert_eq!(serde_json::to_value(&read).unwrap(), expected);
+
+    // bytecode must at least decode again; `false` and `{}` come back as
+    // `null` with the default options
+    let mut out = Vec::new();
+    msg.write(&mut out).unwrap();
+    let opts = ReadOptions {
+        accept_bytecode: true,
+        ..Default::default()
+    };
+    Message::read_with_options(&mut out.as_slice(), &opts).unwrap();
+});
@@ -1,6 +1,8 @@
 // Copyright https://github.com/blahgeek/emacs-lsp-booster
 use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{bail, Result};
 use serde_json as json;
@@ -13,6 +15,8 @@ pub enum LispObject {
     UnibyteStr(Vec<u8>),
     Str(String),
     Int(i64),
+    // integers above `i64::MAX`, only from JSON `u64`s
+    UInt(u64),
     Float(String), // use string for Eq and Ord
     Nil,
     T,
@@ -38,8 +42,8 @@ impl FromStr for LispObject {
 impl LispObject {
     fn to_repl(&self) -> String {
         match self {
-            LispObject::Symbol(s) => s.clone(),
-            LispObject::Keyword(s) => format!(":{}", s),
+            LispObject::Symbol(s) => escape_symbol(s),
+            LispObject::Keyword(s) => format!(":{}", escape_symbol(s)),
             LispObject::Str(s) => {
                 let mut result = String::new();
                 result.reserve(s.len() * 2 + 2);
@@ -104,6 +108,7 @@ impl LispObject {
                 result
             }
             LispObject::Int(i) => i.to_string(),
+            LispObject::UInt(i) => i.to_string(),
             LispObject::Float(s) => s.clone(),
             LispObject::Nil => "nil".into(),
             LispObject::T => "t".into(),
@@ -115,6 +120,19 @@ impl LispObject {
     }
 }
 
+// backslash the characters the Lisp reader would not take as part of a symbol
+// name, like `prin1` does
+fn escape_symbol(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c <= ' ' || c == '\u{a0}' || "\"\\';#()[],`?".contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
 // to support constants more than 65536 elements:
 // - for first 63536 slots, use it as normal
 // - in 63536-64536, put numbers 0-1000, for indexing
@@ -126,6 +144,10 @@ const CV_NORMAL_SLOT_COUNT: u32 = (1 << 16) - CV_TWO_LEVEL_VECTOR_SIZE * 2;
 const CV_TWO_LEVEL_IDX_BEGIN: u32 = CV_NORMAL_SLOT_COUNT;
 const CV_TWO_LEVEL_DATA_BEGIN: u32 = CV_NORMAL_SLOT_COUNT + CV_TWO_LEVEL_VECTOR_SIZE;
 
+// How deep `parse_bytecode_repl` lets vectors and decoded values nest, the
+// same as serde_json's limit when reading JSON.
+const MAX_NESTING: usize = 128;
+
 #[allow(dead_code)]
 enum Op {
     PushConstant(u32), // support more than u16, will expand to multiple ops
@@ -213,12 +235,47 @@ pub enum ObjectType {
     Alist,
 }
 
+/// Called with the error message whenever a message falls back to JSON.
+#[derive(Clone)]
+pub struct FallbackHook(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for FallbackHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FallbackHook")
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct BytecodeOptions {
     pub object_type: ObjectType,
     // TODO: array_type
     pub null_value: LispObject,
     pub false_value: LispObject,
+    // return an error instead of falling back to JSON when encoding fails
+    pub strict: bool,
+    pub on_fallback: Option<FallbackHook>,
+    // significant digits emitted for non-integer numbers, `None` for the shortest
+    // representation that round-trips
+    pub float_precision: Option<usize>,
+    // fail with `BytecodeError::TooLarge` when the output would be larger
+    pub max_bytecode_bytes: Option<usize>,
+    // messages whose JSON is shorter than this are sent as JSON, where the
+    // bytecode would not pay for its encoding
+    pub min_bytecode_bytes: Option<usize>,
+    // encode `false` as `:json-false`, as json.el does, so it can be told apart
+    // from `null`; takes precedence over `false_value`
+    pub false_as_keyword: bool,
+    // put each part of the bytecode object and each constant on its own line,
+    // for reading the output while debugging
+    pub pretty: bool,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum BytecodeError {
+    #[error("bytecode is {size} bytes, exceeding the limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error("invalid bytecode: {0}")]
+    Invalid(String),
 }
 
 impl Default for BytecodeOptions {
@@ -227,6 +284,13 @@ impl Default for BytecodeOptions {
             object_type: ObjectType::Plist,
             null_value: LispObject::Nil,
             false_value: LispObject::Nil,
+            strict: false,
+            on_fallback: None,
+            float_precision: None,
+            max_bytecode_bytes: None,
+            min_bytecode_bytes: None,
+            false_as_keyword: false,
+            pretty: false,
         }
     }
 }
@@ -295,6 +359,9 @@ impl BytecodeCompiler {
             self.compile_constant_op(LispObject::Symbol("list".into()));
         }
 
+        // serde_json is built without `preserve_order`, so `json::Map` is a
+        // `BTreeMap` and keys come out sorted: the same object always
+        // compiles to the same bytecode.
         for (key, value) in map {
             if alist {
                 self.compile_constant_op(LispObject::Symbol(key.clone()));
@@ -345,16 +412,30 @@ impl BytecodeCompiler {
                 self.compile_constant_op(self.options.null_value.clone());
             }
             &json::Value::Bool(false) => {
-                self.compile_constant_op(self.options.false_value.clone());
+                let false_value = if self.options.false_as_keyword {
+                    LispObject::Keyword("json-false".into())
+                } else {
+                    self.options.false_value.clone()
+                };
+                self.compile_constant_op(false_value);
             }
             &json::Value::Bool(true) => {
                 self.compile_constant_op(LispObject::T);
             }
             &json::Value::Number(ref num) => {
                 if num.is_f64() {
-                    self.compile_constant_op(LispObject::Float(num.to_string()));
+                    let s = match self.options.float_precision {
+                        Some(precision) => format_float(num.as_f64().unwrap(), precision),
+                        None => num.to_string(),
+                    };
+                    self.compile_constant_op(LispObject::Float(s));
+                } else if let Some(i) = num.as_i64() {
+                    self.compile_constant_op(LispObject::Int(i));
                 } else {
-                    self.compile_constant_op(LispObject::Int(num.as_i64().unwrap()));
+                    // Integers are printed exactly whatever their size. Emacs 27+
+                    // reads the ones outside the fixnum range (62 bits on 64-bit
+                    // builds) as bignums, older versions read them as floats.
+                    self.compile_constant_op(LispObject::UInt(num.as_u64().unwrap()));
                 }
             }
             &json::Value::String(ref s) => {
@@ -447,24 +528,600 @@ impl BytecodeCompiler {
     }
 
     fn into_repl(self) -> Result<String> {
+        let pretty = self.options.pretty;
         let (code, constants, max_stack_size) = self.into_bytecode()?;
+        let code = LispObject::UnibyteStr(code).to_repl();
+        if !pretty {
+            return Ok(format!(
+                "#[0 {} {} {}]",
+                code,
+                LispObject::Vector(constants).to_repl(),
+                max_stack_size
+            ));
+        }
+        let constants = constants
+            .iter()
+            .map(LispObject::to_repl)
+            .collect::<Vec<_>>()
+            .join("\n   ");
         Ok(format!(
-            "#[0 {} {} {}]",
-            LispObject::UnibyteStr(code).to_repl(),
-            LispObject::Vector(constants).to_repl(),
-            max_stack_size
+            "#[0\n  {}\n  [{}]\n  {}]",
+            code, constants, max_stack_size
         ))
     }
 }
 
+// format `f` with `precision` significant digits, in a form the Lisp reader
+// always reads as a float (e.g. `1.23e-1`)
+fn format_float(f: f64, precision: usize) -> String {
+    let s = format!("{:.*e}", precision.max(1) - 1, f);
+    let (mantissa, exponent) = s.split_once('e').unwrap();
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{}e{}", mantissa, exponent)
+}
+
 pub fn generate_bytecode_repl(value: &json::Value, options: BytecodeOptions) -> Result<String> {
+    let max_bytecode_bytes = options.max_bytecode_bytes;
     let mut compiler = BytecodeCompiler {
         options,
         ops: Vec::new(),
         constants: BTreeMap::new(),
     };
     compiler.compile(value);
-    compiler.into_repl()
+    let repl = compiler.into_repl()?;
+    if let Some(limit) = max_bytecode_bytes {
+        if repl.len() > limit {
+            return Err(BytecodeError::TooLarge {
+                size: repl.len(),
+                limit,
+            }
+            .into());
+        }
+    }
+    Ok(repl)
+}
+
+// The inverse of `generate_bytecode_repl`: read the printed bytecode object and
+// run its code. Only the ops and functions emitted by `BytecodeCompiler` are
+// supported. `nil` decodes to `null`, so `false` and empty objects encoded with
+// the default options come back as `null`.
+pub fn parse_bytecode_repl(s: &str) -> std::result::Result<json::Value, BytecodeError> {
+    let (value, len) = parse_bytecode_prefix(s)?;
+    if !s[len..].trim_start().is_empty() {
+        return Err(invalid_bytecode("trailing data after bytecode object"));
+    }
+    Ok(value)
+}
+
+// Like `parse_bytecode_repl`, for a bytecode object followed by other data
+// such as the next element of a batch. Also returns the length read.
+pub fn parse_bytecode_prefix(s: &str) -> std::result::Result<(json::Value, usize), BytecodeError> {
+    let mut reader = LispReader {
+        input: s.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let function = reader.read()?;
+    let len = reader.pos;
+    let LispObject::Vector(function) = function else {
+        return Err(invalid_bytecode("not a bytecode object"));
+    };
+    let (code, constants) = match function.as_slice() {
+        [LispObject::Int(0), LispObject::UnibyteStr(code), LispObject::Vector(constants), LispObject::Int(_)] => {
+            (code, constants)
+        }
+        _ => return Err(invalid_bytecode("not a bytecode object")),
+    };
+    Ok((BytecodeVm::run(code, constants)?, len))
+}
+
+fn invalid_bytecode(message: impl Into<String>) -> BytecodeError {
+    BytecodeError::Invalid(message.into())
+}
+
+// reads the subset of the lisp syntax printed by `LispObject::to_repl`, with
+// `#[...]` read as a vector and every string read as unibyte
+struct LispReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    // vectors open at `pos`, at most `MAX_NESTING`
+    depth: usize,
+}
+
+impl LispReader<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn next_byte(&mut self) -> std::result::Result<u8, BytecodeError> {
+        let c = *self
+            .input
+            .get(self.pos)
+            .ok_or_else(|| invalid_bytecode("unexpected end of input"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn read(&mut self) -> std::result::Result<LispObject, BytecodeError> {
+        self.skip_whitespace();
+        match self.next_byte()? {
+            b'#' if self.input.get(self.pos) == Some(&b'[') => {
+                self.pos += 1;
+                self.read_vector()
+            }
+            b'[' => self.read_vector(),
+            b'"' => self.read_string(),
+            _ => {
+                self.pos -= 1;
+                self.read_atom()
+            }
+        }
+    }
+
+    fn read_vector(&mut self) -> std::result::Result<LispObject, BytecodeError> {
+        if self.depth == MAX_NESTING {
+            return Err(invalid_bytecode("vectors nested too deeply"));
+        }
+        self.depth += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.input.get(self.pos) == Some(&b']') {
+                self.pos += 1;
+                self.depth -= 1;
+                return Ok(LispObject::Vector(items));
+            }
+            items.push(self.read()?);
+        }
+    }
+
+    fn read_string(&mut self) -> std::result::Result<LispObject, BytecodeError> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next_byte()? {
+                b'"' => return Ok(LispObject::UnibyteStr(bytes)),
+                b'\\' => match self.next_byte()? {
+                    b'a' => bytes.push(7),
+                    b'b' => bytes.push(8),
+                    b't' => bytes.push(9),
+                    b'n' => bytes.push(10),
+                    b'v' => bytes.push(11),
+                    b'f' => bytes.push(12),
+                    b'r' => bytes.push(13),
+                    b'e' => bytes.push(27),
+                    b'd' => bytes.push(127),
+                    // separates an octal escape from a following digit
+                    b' ' => {}
+                    b'^' => bytes.push(self.next_byte()? & 0x1f),
+                    c @ b'0'..=b'7' => {
+                        let mut value = (c - b'0') as u32;
+                        for _ in 0..2 {
+                            match self.input.get(self.pos) {
+                                Some(c @ b'0'..=b'7') => {
+                                    value = value * 8 + (c - b'0') as u32;
+                                    self.pos += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        let byte = u8::try_from(value)
+                            .map_err(|_| invalid_bytecode("octal escape out of range"))?;
+                        bytes.push(byte);
+                    }
+                    c => bytes.push(c),
+                },
+                c => bytes.push(c),
+            }
+        }
+    }
+
+    fn read_atom(&mut self) -> std::result::Result<LispObject, BytecodeError> {
+        let mut token = Vec::new();
+        let mut escaped = false;
+        while let Some(&c) = self.input.get(self.pos) {
+            if c.is_ascii_whitespace() || b"[]\"".contains(&c) {
+                break;
+            }
+            if c == b'\\' {
+                // the next character is part of the name, whatever it is
+                self.pos += 1;
+                escaped = true;
+                token.push(self.next_byte()?);
+                continue;
+            }
+            token.push(c);
+            self.pos += 1;
+        }
+        let token =
+            String::from_utf8(token).map_err(|_| invalid_bytecode("invalid utf-8 in symbol"))?;
+        let token = token.as_str();
+        if token.is_empty() {
+            return Err(invalid_bytecode("unexpected character"));
+        }
+        if escaped {
+            return Ok(match token.strip_prefix(':') {
+                Some(name) => LispObject::Keyword(name.to_string()),
+                None => LispObject::Symbol(token.to_string()),
+            });
+        }
+        if let Ok(obj) = token.parse::<LispObject>() {
+            return Ok(obj);
+        }
+        if let Ok(i) = token.parse::<i64>() {
+            return Ok(LispObject::Int(i));
+        }
+        if let Ok(i) = token.parse::<u64>() {
+            return Ok(LispObject::UInt(i));
+        }
+        let starts_like_number = token
+            .trim_start_matches('-')
+            .starts_with(|c: char| c.is_ascii_digit());
+        if starts_like_number && token.parse::<f64>().is_ok() {
+            return Ok(LispObject::Float(token.to_string()));
+        }
+        Ok(LispObject::Symbol(token.to_string()))
+    }
+}
+
+// values live in `BytecodeVm::values` and refer to each other by index, so
+// copying one with a stack-ref op is cheap and dropping them never recurses
+type ValueId = usize;
+
+#[derive(Debug)]
+enum VmValue {
+    Object(LispObject),
+    Vector(Vec<ValueId>),
+    List(Vec<ValueId>),
+    Cons(ValueId, ValueId),
+    HashTable(Vec<(String, ValueId)>),
+}
+
+struct BytecodeVm<'a> {
+    values: Vec<VmValue>,
+    constants: Vec<ValueId>,
+    code: &'a [u8],
+    pc: usize,
+    stack: Vec<ValueId>,
+    // value slots left to allocate, and JSON nodes left to produce
+    alloc_budget: usize,
+    json_budget: usize,
+}
+
+impl BytecodeVm<'_> {
+    fn run(
+        code: &[u8],
+        constants: &[LispObject],
+    ) -> std::result::Result<json::Value, BytecodeError> {
+        // Bytecode from `BytecodeCompiler` allocates one value per op and
+        // never expands a value twice, the headroom is for other producers.
+        let budget = (code.len() + constants.len()) * 4 + 1024;
+        let mut vm = BytecodeVm {
+            values: Vec::new(),
+            constants: Vec::new(),
+            code,
+            pc: 0,
+            stack: Vec::new(),
+            alloc_budget: budget,
+            json_budget: budget,
+        };
+        for constant in constants {
+            let id = vm.alloc_constant(constant)?;
+            vm.constants.push(id);
+        }
+        loop {
+            let op = vm.fetch()?;
+            match op {
+                1..=4 => {
+                    let depth = op as usize;
+                    let value = vm
+                        .stack
+                        .len()
+                        .checked_sub(depth + 1)
+                        .map(|i| vm.stack[i])
+                        .ok_or_else(|| invalid_bytecode("stack underflow"))?;
+                    vm.stack.push(value);
+                }
+                32..=39 => {
+                    let argc = match op {
+                        38 => vm.fetch()? as usize,
+                        39 => vm.fetch_u16()? as usize,
+                        _ => (op - 32) as usize,
+                    };
+                    let args = vm.pop_n(argc)?;
+                    let function = vm.pop()?;
+                    let result = vm.call(function, args)?;
+                    vm.stack.push(result);
+                }
+                66 => {
+                    let cdr = vm.pop()?;
+                    let car = vm.pop()?;
+                    let cons = vm.alloc(VmValue::Cons(car, cdr), 2)?;
+                    vm.stack.push(cons);
+                }
+                67..=70 | 175 => {
+                    let len = match op {
+                        175 => vm.fetch()? as usize,
+                        _ => (op - 66) as usize,
+                    };
+                    let items = vm.pop_n(len)?;
+                    let list = vm.alloc(VmValue::List(items), len)?;
+                    vm.stack.push(list);
+                }
+                72 => {
+                    let index = vm.pop()?;
+                    let vector = vm.pop()?;
+                    let value = match (&vm.values[vector], &vm.values[index]) {
+                        (VmValue::Vector(items), VmValue::Object(LispObject::Int(i))) => {
+                            usize::try_from(*i).ok().and_then(|i| items.get(i).copied())
+                        }
+                        _ => None,
+                    };
+                    vm.stack
+                        .push(value.ok_or_else(|| invalid_bytecode("invalid aref"))?);
+                }
+                129 => {
+                    let index = vm.fetch_u16()? as usize;
+                    let value = vm.constant(index)?;
+                    vm.stack.push(value);
+                }
+                135 => {
+                    let value = vm.pop()?;
+                    return vm.json_of(value, 0);
+                }
+                136 => {
+                    vm.pop()?;
+                }
+                192..=255 => {
+                    let value = vm.constant((op - 192) as usize)?;
+                    vm.stack.push(value);
+                }
+                op => return Err(invalid_bytecode(format!("unsupported op {}", op))),
+            }
+        }
+    }
+
+    fn alloc(
+        &mut self,
+        value: VmValue,
+        slots: usize,
+    ) -> std::result::Result<ValueId, BytecodeError> {
+        self.alloc_budget = self
+            .alloc_budget
+            .checked_sub(slots + 1)
+            .ok_or_else(|| invalid_bytecode("value too large"))?;
+        self.values.push(value);
+        Ok(self.values.len() - 1)
+    }
+
+    // constants nest no deeper than `LispReader` allows
+    fn alloc_constant(&mut self, obj: &LispObject) -> std::result::Result<ValueId, BytecodeError> {
+        match obj {
+            LispObject::Vector(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| self.alloc_constant(item))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let len = items.len();
+                self.alloc(VmValue::Vector(items), len)
+            }
+            obj => self.alloc(VmValue::Object(obj.clone()), 0),
+        }
+    }
+
+    fn constant(&self, index: usize) -> std::result::Result<ValueId, BytecodeError> {
+        self.constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| invalid_bytecode("constant index out of range"))
+    }
+
+    fn call(
+        &mut self,
+        function: ValueId,
+        args: Vec<ValueId>,
+    ) -> std::result::Result<ValueId, BytecodeError> {
+        let VmValue::Object(LispObject::Symbol(name)) = &self.values[function] else {
+            return Err(invalid_bytecode("calling a non-symbol"));
+        };
+        match name.as_str() {
+            "vector" => {
+                let len = args.len();
+                self.alloc(VmValue::Vector(args), len)
+            }
+            "list" => {
+                let len = args.len();
+                self.alloc(VmValue::List(args), len)
+            }
+            "vconcat" => {
+                let mut result = Vec::new();
+                for arg in args {
+                    let VmValue::Vector(items) = &self.values[arg] else {
+                        return Err(invalid_bytecode("vconcat of a non-vector"));
+                    };
+                    if result.len() + items.len() > self.alloc_budget {
+                        return Err(invalid_bytecode("value too large"));
+                    }
+                    result.extend_from_slice(items);
+                }
+                let len = result.len();
+                self.alloc(VmValue::Vector(result), len)
+            }
+            "make-hash-table" => self.alloc(VmValue::HashTable(Vec::new()), 0),
+            "puthash" => {
+                let [key, value, table]: [ValueId; 3] = args
+                    .try_into()
+                    .map_err(|_| invalid_bytecode("puthash takes 3 arguments"))?;
+                let VmValue::Object(LispObject::UnibyteStr(key)) = &self.values[key] else {
+                    return Err(invalid_bytecode("invalid puthash arguments"));
+                };
+                let key = String::from_utf8(key.clone())
+                    .map_err(|_| invalid_bytecode("invalid utf-8 in key"))?;
+                self.alloc_budget = self
+                    .alloc_budget
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid_bytecode("value too large"))?;
+                let VmValue::HashTable(entries) = &mut self.values[table] else {
+                    return Err(invalid_bytecode("invalid puthash arguments"));
+                };
+                entries.push((key, value));
+                Ok(value)
+            }
+            name => Err(invalid_bytecode(format!("unsupported function {}", name))),
+        }
+    }
+
+    // the elements of a proper list, built either by `list` or by conses
+    fn list_items(&self, mut rest: ValueId) -> Option<Vec<ValueId>> {
+        let mut items = Vec::new();
+        loop {
+            match &self.values[rest] {
+                VmValue::Object(LispObject::Nil) => return Some(items),
+                VmValue::List(tail) => {
+                    items.extend_from_slice(tail);
+                    return Some(items);
+                }
+                VmValue::Cons(car, cdr) => {
+                    items.push(*car);
+                    rest = *cdr;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // Fails on values nested deeper than `MAX_NESTING`, and on values that
+    // expand to more nodes than the bytecode could describe, as shared values
+    // referenced over and over do.
+    fn json_of(
+        &mut self,
+        id: ValueId,
+        depth: usize,
+    ) -> std::result::Result<json::Value, BytecodeError> {
+        if depth > MAX_NESTING {
+            return Err(invalid_bytecode("value nested too deeply"));
+        }
+        self.json_budget = self
+            .json_budget
+            .checked_sub(1)
+            .ok_or_else(|| invalid_bytecode("value too large"))?;
+        let value = match &self.values[id] {
+            VmValue::Object(LispObject::Nil) => json::Value::Null,
+            VmValue::Object(LispObject::T) => json::Value::Bool(true),
+            VmValue::Object(LispObject::Keyword(k)) if k == "json-false" => {
+                json::Value::Bool(false)
+            }
+            VmValue::Object(LispObject::Int(i)) => json::Value::from(*i),
+            VmValue::Object(LispObject::UInt(i)) => json::Value::from(*i),
+            VmValue::Object(LispObject::Float(f)) => f
+                .parse::<f64>()
+                .ok()
+                .and_then(json::Number::from_f64)
+                .map(json::Value::Number)
+                .ok_or_else(|| invalid_bytecode(format!("invalid float {}", f)))?,
+            VmValue::Object(LispObject::UnibyteStr(bytes)) => json::Value::String(
+                String::from_utf8(bytes.clone())
+                    .map_err(|_| invalid_bytecode("invalid utf-8 in string"))?,
+            ),
+            VmValue::Object(LispObject::Str(s)) => json::Value::String(s.clone()),
+            VmValue::Object(obj) => {
+                return Err(invalid_bytecode(format!(
+                    "unexpected value {}",
+                    obj.to_repl()
+                )))
+            }
+            VmValue::Vector(items) => {
+                let items = items.clone();
+                json::Value::Array(
+                    items
+                        .into_iter()
+                        .map(|item| self.json_of(item, depth + 1))
+                        .collect::<std::result::Result<_, _>>()?,
+                )
+            }
+            VmValue::HashTable(entries) => {
+                let entries = entries.clone();
+                let mut map = json::Map::new();
+                for (key, value) in entries {
+                    map.insert(key, self.json_of(value, depth + 1)?);
+                }
+                json::Value::Object(map)
+            }
+            VmValue::List(_) | VmValue::Cons(_, _) => {
+                let items = self
+                    .list_items(id)
+                    .ok_or_else(|| invalid_bytecode("unexpected cons cell"))?;
+                let mut map = json::Map::new();
+                let is_plist = items.len() % 2 == 0
+                    && items.iter().step_by(2).all(|&key| {
+                        matches!(self.values[key], VmValue::Object(LispObject::Keyword(_)))
+                    });
+                if is_plist {
+                    for pair in items.chunks(2) {
+                        let VmValue::Object(LispObject::Keyword(key)) = &self.values[pair[0]]
+                        else {
+                            unreachable!()
+                        };
+                        let key = key.clone();
+                        map.insert(key, self.json_of(pair[1], depth + 1)?);
+                    }
+                } else {
+                    // alist
+                    for item in items {
+                        let VmValue::Cons(key, value) = self.values[item] else {
+                            return Err(invalid_bytecode("list is neither a plist nor an alist"));
+                        };
+                        let VmValue::Object(LispObject::Symbol(key)) = &self.values[key] else {
+                            return Err(invalid_bytecode("alist key is not a symbol"));
+                        };
+                        let key = key.clone();
+                        map.insert(key, self.json_of(value, depth + 1)?);
+                    }
+                }
+                json::Value::Object(map)
+            }
+        };
+        Ok(value)
+    }
+
+    fn fetch(&mut self) -> std::result::Result<u8, BytecodeError> {
+        let op = *self
+            .code
+            .get(self.pc)
+            .ok_or_else(|| invalid_bytecode("code ended without return"))?;
+        self.pc += 1;
+        Ok(op)
+    }
+
+    fn fetch_u16(&mut self) -> std::result::Result<u16, BytecodeError> {
+        let lo = self.fetch()? as u16;
+        let hi = self.fetch()? as u16;
+        Ok(lo | (hi << 8))
+    }
+
+    fn pop(&mut self) -> std::result::Result<ValueId, BytecodeError> {
+        self.stack
+            .pop()
+            .ok_or_else(|| invalid_bytecode("stack underflow"))
+    }
+
+    fn pop_n(&mut self, n: usize) -> std::result::Result<Vec<ValueId>, BytecodeError> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(n)
+            .ok_or_else(|| invalid_bytecode("stack underflow"))?;
+        Ok(self.stack.split_off(start))
+    }
 }
 
 #[test]
@@ -475,3 +1132,238 @@ fn test_string_repl() {
     assert_eq!(LispObject::UnibyteStr("\x7f".into()).to_repl(), r#""\d""#);
     assert_eq!(LispObject::UnibyteStr(vec![0xff]).to_repl(), r#""\377""#);
 }
+
+#[test]
+fn test_float_precision() {
+    assert_eq!(format_float(0.1, 3), "1e-1");
+    assert_eq!(format_float(0.123456, 3), "1.23e-1");
+    assert_eq!(format_float(1.2345678901234567e300, 4), "1.235e300");
+    assert_eq!(format_float(-2.6, 0), "-3e0");
+
+    let encode = |value: json::Value, float_precision| {
+        let options = BytecodeOptions {
+            float_precision,
+            ..Default::default()
+        };
+        generate_bytecode_repl(&value, options).unwrap()
+    };
+    assert!(encode(json::json!(0.1), Some(3)).contains("[1e-1]"));
+    assert!(encode(json::json!(0.1), None).contains("[0.1]"));
+    assert!(encode(json::json!(1.2345678901234567e300), Some(3)).contains("[1.23e300]"));
+    assert_eq!(
+        encode(json::json!(42), Some(3)),
+        encode(json::json!(42), None)
+    );
+    assert!(encode(json::json!(42), Some(3)).contains("[42]"));
+}
+
+#[test]
+fn test_pretty() {
+    let value =
+        json::json!({"items": [{"label": "foo", "data": [1, null]}], "isIncomplete": false});
+    let encode = |pretty| {
+        let options = BytecodeOptions {
+            pretty,
+            ..Default::default()
+        };
+        generate_bytecode_repl(&value, options).unwrap()
+    };
+    let compact = encode(false);
+    let pretty = encode(true);
+    assert!(!compact.contains('\n'));
+    assert!(pretty.starts_with("#[0\n  \""));
+    assert!(pretty.contains("\n   :items"));
+    assert_eq!(
+        pretty.split_whitespace().collect::<Vec<_>>(),
+        compact.split_whitespace().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        parse_bytecode_repl(&pretty).unwrap(),
+        parse_bytecode_repl(&compact).unwrap()
+    );
+}
+
+// Fails if serde_json's `preserve_order` gets enabled, which would make the
+// output depend on the order keys were inserted in.
+#[test]
+fn test_key_order() {
+    let options = BytecodeOptions::default();
+    let mut forward = json::Map::new();
+    let mut backward = json::Map::new();
+    for key in ["label", "kind", "detail", "data"] {
+        forward.insert(key.to_string(), json::json!(key.len()));
+    }
+    for key in ["data", "detail", "kind", "label"] {
+        backward.insert(key.to_string(), json::json!(key.len()));
+    }
+    let forward = json::json!({"items": [forward]});
+    let backward = json::json!({"items": [backward]});
+    let encoded = generate_bytecode_repl(&forward, options.clone()).unwrap();
+    assert_eq!(
+        generate_bytecode_repl(&forward, options.clone()).unwrap(),
+        encoded
+    );
+    assert_eq!(generate_bytecode_repl(&backward, options).unwrap(), encoded);
+
+    let hashtable = BytecodeOptions {
+        object_type: ObjectType::Hashtable,
+        ..Default::default()
+    };
+    assert_eq!(
+        generate_bytecode_repl(&forward, hashtable.clone()).unwrap(),
+        generate_bytecode_repl(&backward, hashtable).unwrap()
+    );
+}
+
+#[test]
+fn test_large_integers() {
+    let values = [
+        json::json!(i64::MAX),
+        json::json!(i32::MAX as i64 + 1),
+        json::json!(-1_234_567_890_123_i64),
+        json::json!(i64::MIN),
+        json::json!(u64::MAX),
+    ];
+    for value in values {
+        let repl = generate_bytecode_repl(&value, Default::default()).unwrap();
+        assert!(repl.contains(&format!("[{}]", value)), "{repl}");
+        assert_eq!(parse_bytecode_repl(&repl).unwrap(), value);
+    }
+}
+
+#[test]
+fn test_escape_keyword() {
+    let value = json::json!({"a b": 1, "x\"y\\z": 2, "(;#?)\n": 3, "é世": 4, "": 5});
+    let repl = generate_bytecode_repl(&value, Default::default()).unwrap();
+    assert!(repl.contains(r#":a\ b"#), "{repl}");
+    assert!(repl.contains(r#":x\"y\\z"#), "{repl}");
+    assert!(repl.contains(":\\(\\;\\#\\?\\)\\\n"), "{repl}");
+    assert_eq!(parse_bytecode_repl(&repl).unwrap(), value);
+}
+
+#[test]
+fn test_null_and_booleans() {
+    let value = json::json!({"n": null, "t": true, "f": false});
+    let encode = |false_as_keyword| {
+        let options = BytecodeOptions {
+            false_as_keyword,
+            ..Default::default()
+        };
+        generate_bytecode_repl(&value, options).unwrap()
+    };
+
+    let default = encode(false);
+    assert!(default.contains("[nil :f :n :t t]"), "{default}");
+    assert!(!default.contains(":json-false"), "{default}");
+    // `false` reads back as `null`
+    assert_eq!(
+        parse_bytecode_repl(&default).unwrap(),
+        json::json!({"n": null, "t": true, "f": null})
+    );
+
+    let keyword = encode(true);
+    assert!(keyword.contains(":json-false"), "{keyword}");
+    assert_eq!(parse_bytecode_repl(&keyword).unwrap(), value);
+}
+
+#[test]
+fn test_max_bytecode_bytes() {
+    let value = json::json!((0..1000).collect::<Vec<i32>>());
+    let size = generate_bytecode_repl(&value, Default::default())
+        .unwrap()
+        .len();
+
+    let options = BytecodeOptions {
+        max_bytecode_bytes: Some(100),
+        ..Default::default()
+    };
+    let err = generate_bytecode_repl(&value, options).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BytecodeError>(),
+        Some(&BytecodeError::TooLarge { size, limit: 100 })
+    );
+
+    let options = BytecodeOptions {
+        max_bytecode_bytes: Some(size),
+        ..Default::default()
+    };
+    assert!(generate_bytecode_repl(&value, options).is_ok());
+}
+
+#[test]
+fn test_parse_bytecode_repl() {
+    let values = [
+        json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/completion",
+            "params": {"items": [{"label": "foo \"bar\"\n\u{1}", "kind": 3}], "empty": []},
+        }),
+        json::json!([1, -2, 0.5, "héllo 世界", true, null, [[]]]),
+        json::json!((0..300)
+            .map(|i| (format!("k{i}"), json::json!(i)))
+            .collect::<json::Map<_, _>>()),
+    ];
+    for value in values {
+        for object_type in [ObjectType::Plist, ObjectType::Alist, ObjectType::Hashtable] {
+            let options = BytecodeOptions {
+                object_type,
+                ..Default::default()
+            };
+            let repl = generate_bytecode_repl(&value, options).unwrap();
+            assert_eq!(
+                parse_bytecode_repl(&repl).unwrap(),
+                value,
+                "{object_type:?}"
+            );
+        }
+    }
+
+    // more constants than fit in the constant vector directly
+    let value = json::json!((0..70000).collect::<Vec<i32>>());
+    let repl = generate_bytecode_repl(&value, Default::default()).unwrap();
+    assert_eq!(parse_bytecode_repl(&repl).unwrap(), value);
+
+    assert!(matches!(
+        parse_bytecode_repl("#[0 \"\" []"),
+        Err(BytecodeError::Invalid(_))
+    ));
+    assert!(matches!(
+        parse_bytecode_repl("{}"),
+        Err(BytecodeError::Invalid(_))
+    ));
+}
+
+#[test]
+fn test_parse_bytecode_limits() {
+    let invalid = |repl: &str| match parse_bytecode_repl(repl) {
+        Err(BytecodeError::Invalid(message)) => message,
+        result => panic!("unexpected result {:?}", result),
+    };
+    let repl = |code: Vec<u8>| {
+        format!(
+            "#[0 {} [vector 1] 4]",
+            LispObject::UnibyteStr(code).to_repl()
+        )
+    };
+    assert_eq!(
+        invalid(&format!("#{}", "[".repeat(1_000_000))),
+        "vectors nested too deeply"
+    );
+
+    // `(vector (vector ... 1))`, each round wraps the value on top
+    let mut code = vec![193];
+    for _ in 0..200 {
+        code.extend([192, 1, 33]);
+    }
+    code.push(135);
+    assert_eq!(invalid(&repl(code)), "value nested too deeply");
+
+    // each round builds `(vector v v)` from the value on top, doubling its size
+    let mut code = vec![193];
+    for _ in 0..64 {
+        code.extend([192, 1, 2, 34]);
+    }
+    code.push(135);
+    assert_eq!(invalid(&repl(code)), "value too large");
+}
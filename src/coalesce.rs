@@ -0,0 +1,225 @@
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lsp_types::notification::{DidChangeConfiguration, DidChangeTextDocument, Notification as _};
+use serde_json::Value;
+
+use crate::msg::Notification;
+
+/// Collapses bursts of `textDocument/didChange` notifications for the same
+/// uri into one, so fast typing does not flood the language server.
+///
+/// The surviving notification is the latest one, carrying the content changes
+/// of the whole burst in order, so incremental changes are not lost. Any other
+/// notification flushes the pending ones first to keep the original order.
+///
+/// `workspace/didChangeConfiguration` notifications are debounced instead:
+/// only the latest is forwarded, once no other one came for a whole window.
+#[derive(Debug)]
+pub struct NotificationCoalescer {
+    window: Duration,
+    /// Keyed by `(method, uri)`, with the time the burst started, or the time
+    /// of the latest configuration change. Configuration changes have an
+    /// empty uri.
+    pending: HashMap<(String, String), (Notification, Instant)>,
+    /// Keys of `pending` in arrival order.
+    order: Vec<(String, String)>,
+}
+
+impl NotificationCoalescer {
+    pub fn new(window: Duration) -> NotificationCoalescer {
+        NotificationCoalescer {
+            window,
+            pending: HashMap::new(),
+            order: Vec::new(),
+        }
+    }
+
+    /// Buffer `not` if it can be coalesced, returns the notifications to
+    /// forward now.
+    pub fn push(&mut self, not: Notification, now: Instant) -> Vec<Notification> {
+        let uri = match not.uri() {
+            Some(uri) if not.method == DidChangeTextDocument::METHOD => uri,
+            _ if not.method == DidChangeConfiguration::METHOD => String::new(),
+            _ => {
+                let mut ready = self.flush();
+                ready.push(not);
+                return ready;
+            }
+        };
+        let key = (not.method.clone(), uri);
+        match self.pending.remove(&key) {
+            Some(_) if not.method == DidChangeConfiguration::METHOD => {
+                self.pending.insert(key, (not, now));
+            }
+            Some((older, started)) => {
+                self.pending
+                    .insert(key, (merge_changes(older, not), started));
+            }
+            None => {
+                self.order.push(key.clone());
+                self.pending.insert(key, (not, now));
+            }
+        }
+        Vec::new()
+    }
+
+    /// Returns the notifications whose window has passed by `now`.
+    pub fn poll(&mut self, now: Instant) -> Vec<Notification> {
+        let mut ready = Vec::new();
+        let pending = &mut self.pending;
+        let window = self.window;
+        self.order.retain(|key| {
+            let expired = pending
+                .get(key)
+                .is_some_and(|(_, started)| now.duration_since(*started) >= window);
+            if expired {
+                ready.push(pending.remove(key).unwrap().0);
+            }
+            !expired
+        });
+        ready
+    }
+
+    /// Returns all buffered notifications.
+    pub fn flush(&mut self) -> Vec<Notification> {
+        self.order
+            .drain(..)
+            .filter_map(|key| self.pending.remove(&key))
+            .map(|(not, _)| not)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// `newer` with the content changes of `older` prepended. Changes before the
+/// last full-text change (one without a `range`) are dropped.
+fn merge_changes(older: Notification, mut newer: Notification) -> Notification {
+    let mut changes = match older.params.params.get("contentChanges") {
+        Some(Value::Array(changes)) => changes.clone(),
+        _ => Vec::new(),
+    };
+    if let Some(Value::Array(newer_changes)) = newer.params.params.get("contentChanges") {
+        changes.extend(newer_changes.iter().cloned());
+    }
+    if let Some(last_full) = changes
+        .iter()
+        .rposition(|change| change.get("range").is_none())
+    {
+        changes.drain(..last_full);
+    }
+    if let Some(params) = newer.params.params.as_object_mut() {
+        params.insert("contentChanges".to_string(), Value::Array(changes));
+    }
+    newer
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn did_change(uri: &str, version: i32, change: Value) -> Notification {
+        Notification::new_with(
+            DidChangeTextDocument::METHOD.to_string(),
+            Some(uri.to_string()),
+            None,
+            json!({
+                "textDocument": {"uri": uri, "version": version},
+                "contentChanges": [change],
+            }),
+        )
+    }
+
+    fn insert(text: &str) -> Value {
+        json!({
+            "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}},
+            "text": text,
+        })
+    }
+
+    #[test]
+    fn test_coalesce_did_change() {
+        let window = Duration::from_millis(50);
+        let mut coalescer = NotificationCoalescer::new(window);
+        let start = Instant::now();
+
+        for (version, text) in [(1, "a"), (2, "b"), (3, "c")] {
+            let not = did_change("file:///a.rs", version, insert(text));
+            assert!(coalescer.push(not, start).is_empty());
+        }
+        assert!(coalescer.poll(start).is_empty());
+
+        let ready = coalescer.poll(start + window);
+        assert_eq!(ready.len(), 1);
+        let params = &ready[0].params.params;
+        assert_eq!(params["textDocument"]["version"], 3);
+        let texts: Vec<&Value> = params["contentChanges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| &change["text"])
+            .collect();
+        assert_eq!(texts, ["a", "b", "c"]);
+        assert!(coalescer.is_empty());
+    }
+
+    #[test]
+    fn test_debounce_configuration() {
+        let window = Duration::from_millis(50);
+        let mut coalescer = NotificationCoalescer::new(window);
+        let start = Instant::now();
+
+        for i in 0..3 {
+            let not = Notification::new(
+                DidChangeConfiguration::METHOD.to_string(),
+                json!({"settings": {"revision": i}}),
+            );
+            let now = start + Duration::from_millis(30) * i;
+            assert!(coalescer.push(not, now).is_empty());
+        }
+        // each change restarts the quiet period
+        assert!(coalescer
+            .poll(start + Duration::from_millis(100))
+            .is_empty());
+
+        let ready = coalescer.poll(start + Duration::from_millis(110));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].params.params["settings"]["revision"], 2);
+        assert!(coalescer.is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_order() {
+        let mut coalescer = NotificationCoalescer::new(Duration::from_secs(1));
+        let now = Instant::now();
+
+        coalescer.push(did_change("file:///a.rs", 1, insert("a")), now);
+        coalescer.push(did_change("file:///b.rs", 1, insert("b")), now);
+        coalescer.push(did_change("file:///a.rs", 2, json!({"text": "full"})), now);
+
+        let save = Notification::new_with(
+            "textDocument/didSave".to_string(),
+            Some("file:///a.rs".to_string()),
+            None,
+            json!({"textDocument": {"uri": "file:///a.rs"}}),
+        );
+        let ready = coalescer.push(save, now);
+        let uris: Vec<_> = ready.iter().map(|not| not.uri().unwrap()).collect();
+        assert_eq!(uris, ["file:///a.rs", "file:///b.rs", "file:///a.rs"]);
+        assert_eq!(ready[2].method, "textDocument/didSave");
+        // the full-text change replaces the earlier one
+        assert_eq!(
+            ready[0].params.params["contentChanges"],
+            json!([{"text": "full"}])
+        );
+    }
+}
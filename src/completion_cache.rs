@@ -1,4 +1,9 @@
-use crate::lsp_ext::CompletionItem;
+// Not all of the cache is used by the main loop yet.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{lsp_ext::CompletionItem, msg::ResolveContext};
 use log::debug;
 
 pub struct CompletionCache {
@@ -87,3 +92,108 @@ impl CompletionCache {
         }
     }
 }
+
+/// `(language_server_id, start, end)` of a completion item, as sent back in
+/// a `ResolveContext`.
+pub type ResolveKey = (usize, i32, i32);
+
+/// The completion items last sent to Emacs, so a resolve request can start
+/// from the original item instead of querying the server again.
+///
+/// Holds at most `capacity` items, evicting the least recently used.
+pub struct ResolveCache {
+    capacity: usize,
+    items: HashMap<ResolveKey, lsp_types::CompletionItem>,
+    /// Keys of `items`, least recently used first.
+    order: VecDeque<ResolveKey>,
+}
+
+impl ResolveCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            items: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: ResolveKey, item: lsp_types::CompletionItem) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.items.insert(key, item).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &ResolveKey) -> Option<&lsp_types::CompletionItem> {
+        if !self.items.contains_key(key) {
+            return None;
+        }
+        self.touch(key);
+        self.items.get(key)
+    }
+
+    pub fn get_for(&mut self, context: &ResolveContext) -> Option<&lsp_types::CompletionItem> {
+        self.get(&(context.language_server_id, context.start, context.end))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn touch(&mut self, key: &ResolveKey) {
+        if let Some(index) = self.order.iter().position(|k| k == key) {
+            self.order.remove(index);
+        }
+        self.order.push_back(*key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn item(label: &str) -> lsp_types::CompletionItem {
+        lsp_types::CompletionItem::new_simple(label.to_string(), String::new())
+    }
+
+    #[test]
+    fn test_resolve_cache() {
+        let mut cache = ResolveCache::new(2);
+        cache.insert((0, 4, 7), item("foo"));
+        cache.insert((1, 4, 7), item("bar"));
+
+        assert_eq!(cache.get(&(0, 4, 7)).unwrap().label, "foo");
+        let context = ResolveContext {
+            language_server_id: 1,
+            start: 4,
+            end: 7,
+        };
+        assert_eq!(cache.get_for(&context).unwrap().label, "bar");
+        assert!(cache.get(&(0, 0, 7)).is_none());
+
+        // `(0, 4, 7)` is the least recently used
+        cache.insert((0, 8, 9), item("baz"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&(0, 4, 7)).is_none());
+        assert_eq!(cache.get(&(1, 4, 7)).unwrap().label, "bar");
+        assert_eq!(cache.get(&(0, 8, 9)).unwrap().label, "baz");
+
+        // replacing an item keeps the size
+        cache.insert((1, 4, 7), item("qux"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&(1, 4, 7)).unwrap().label, "qux");
+    }
+}
@@ -5,8 +5,9 @@ use std::{
 
 use crossbeam_channel::{bounded, Receiver, Sender};
 // use log::debug;
+use log::error;
 
-use crate::msg::Message;
+use crate::{error::TruncatedMessage, msg::Message};
 
 pub struct Connection {
     pub sender: Sender<Message>,
@@ -32,8 +33,12 @@ impl Connection {
         let reader = thread::spawn(move || {
             let stdin = stdin();
             let mut stdin = stdin.lock();
-            while let Some(msg) = Message::read(&mut stdin)? {
-                let is_exit = matches!(&msg, Message::Notification(n) if n.is_exit());
+            while let Some(msg) = Message::read(&mut stdin).inspect_err(|err| {
+                if TruncatedMessage::of(err).is_some() {
+                    error!("client disconnected in the middle of a message: {}", err);
+                }
+            })? {
+                let is_exit = msg.as_notification().is_some_and(|n| n.is_exit());
                 // debug!("sending message {:#?}", msg);
                 // 从 stdin 读取到输入，通过 sender 发送给处理程序
                 reader_sender
@@ -0,0 +1,103 @@
+//! Suppression of `textDocument/publishDiagnostics` notifications that
+//! repeat the diagnostics Emacs already has.
+
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use lsp_types::notification::{Notification as _, PublishDiagnostics};
+use serde_json::Value;
+
+use crate::msg::Notification;
+
+/// Remembers, per uri, the version and a hash of the diagnostics last
+/// forwarded, so identical diagnostics for an unchanged document are sent
+/// only once.
+#[derive(Debug, Default)]
+pub struct DiagnosticsDeduper {
+    last: HashMap<String, (Option<i64>, u64)>,
+}
+
+impl DiagnosticsDeduper {
+    pub fn new() -> DiagnosticsDeduper {
+        DiagnosticsDeduper::default()
+    }
+
+    /// Whether `not` should be forwarded. Only `publishDiagnostics` equal to
+    /// the last one for its uri, same version included, is suppressed.
+    pub fn should_forward(&mut self, not: &Notification) -> bool {
+        if not.method != PublishDiagnostics::METHOD {
+            return true;
+        }
+        let params = &not.params.params;
+        let Some(uri) = not
+            .uri()
+            .or_else(|| params.get("uri")?.as_str().map(str::to_owned))
+        else {
+            return true;
+        };
+        let version = params.get("version").and_then(Value::as_i64);
+        let hash = hash_diagnostics(params.get("diagnostics").unwrap_or(&Value::Null));
+        self.last.insert(uri, (version, hash)) != Some((version, hash))
+    }
+
+    /// Forget `uri`, e.g. once the document is closed.
+    pub fn forget(&mut self, uri: &str) {
+        self.last.remove(uri);
+    }
+}
+
+fn hash_diagnostics(diagnostics: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    diagnostics.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn publish(uri: &str, version: i32, message: &str) -> Notification {
+        Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            json!({
+                "uri": uri,
+                "version": version,
+                "diagnostics": [{
+                    "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 1}},
+                    "message": message,
+                }],
+            }),
+        )
+    }
+
+    #[test]
+    fn test_identical_diagnostics_suppressed() {
+        let mut deduper = DiagnosticsDeduper::new();
+        assert!(deduper.should_forward(&publish("file:///a.rs", 1, "unused")));
+        assert!(!deduper.should_forward(&publish("file:///a.rs", 1, "unused")));
+        // other uris are tracked apart
+        assert!(deduper.should_forward(&publish("file:///b.rs", 1, "unused")));
+
+        deduper.forget("file:///a.rs");
+        assert!(deduper.should_forward(&publish("file:///a.rs", 1, "unused")));
+    }
+
+    #[test]
+    fn test_changed_diagnostics_forwarded() {
+        let mut deduper = DiagnosticsDeduper::new();
+        assert!(deduper.should_forward(&publish("file:///a.rs", 1, "unused")));
+        assert!(deduper.should_forward(&publish("file:///a.rs", 1, "mismatched types")));
+        // a new version is forwarded even with the same diagnostics
+        assert!(deduper.should_forward(&publish("file:///a.rs", 2, "mismatched types")));
+
+        let log = Notification::new("window/logMessage".to_string(), json!({"message": "x"}));
+        assert!(deduper.should_forward(&log));
+        assert!(deduper.should_forward(&log));
+    }
+}
@@ -63,8 +63,7 @@ impl RequestDispatcher {
         match res {
             Ok(params) => Some((req, params)),
             Err(err) => {
-                let response =
-                    Response::new_err(req.id, ErrorCode::InvalidParams as i32, err.to_string());
+                let response = Response::new_err(req.id, ErrorCode::InvalidParams, err.to_string());
                 self.sender.send(response.into()).unwrap();
                 None
             }
@@ -76,7 +75,7 @@ impl RequestDispatcher {
             error!("unknown request: {:?}", req);
             let response = Response::new_err(
                 req.id,
-                ErrorCode::MethodNotFound as i32,
+                ErrorCode::MethodNotFound,
                 "unknown request".to_string(),
             );
             self.sender.send(response.into()).unwrap();
@@ -105,7 +104,7 @@ impl NotificationDispatcher<'_> {
 
         let params = match not.extract::<N::Params>(N::METHOD) {
             Ok(it) => it,
-            Err(ExtractError::JsonError { method, error }) => {
+            Err(ExtractError::JsonError { method, error, .. }) => {
                 panic!("Invald request\nMethod: {method}\n error: {error}");
             }
             Err(ExtractError::MethodMismatch(not)) => {
@@ -1,4 +1,4 @@
-use crate::msg::{Notification, Request};
+use crate::msg::{Notification, Request, RequestId, ResponseError};
 use core::fmt;
 
 #[derive(Debug)]
@@ -9,6 +9,8 @@ pub enum ExtractError<T> {
     JsonError {
         method: String,
         error: serde_json::Error,
+        /// The params that failed to deserialize.
+        params: serde_json::Value,
     },
 }
 
@@ -19,8 +21,15 @@ impl fmt::Display for ExtractError<Request> {
             ExtractError::MethodMismatch(req) => {
                 write!(f, "Method mismatch for request '{}'", req.method)
             }
-            ExtractError::JsonError { method, error } => {
-                write!(f, "Invalid request\nMethod: {method}\n error: {error}",)
+            ExtractError::JsonError {
+                method,
+                error,
+                params,
+            } => {
+                write!(
+                    f,
+                    "Invalid request\nMethod: {method}\n error: {error}\n params: {params}",
+                )
             }
         }
     }
@@ -33,9 +42,202 @@ impl fmt::Display for ExtractError<Notification> {
             ExtractError::MethodMismatch(req) => {
                 write!(f, "Method mismatch for notification '{}'", req.method)
             }
-            ExtractError::JsonError { method, error } => {
-                write!(f, "Invalid notification\nMethod: {method}\n error:{error}")
+            ExtractError::JsonError {
+                method,
+                error,
+                params,
+            } => {
+                write!(
+                    f,
+                    "Invalid notification\nMethod: {method}\n error:{error}\n params: {params}"
+                )
             }
         }
     }
 }
+
+impl std::error::Error for ExtractError<serde_json::Value> {}
+impl fmt::Display for ExtractError<serde_json::Value> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::MethodMismatch(value) => {
+                write!(f, "Unexpected message: {value}")
+            }
+            ExtractError::JsonError {
+                method,
+                error,
+                params,
+            } => {
+                write!(
+                    f,
+                    "Invalid message\nMethod: {method}\n error: {error}\n value: {params}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ResponseExtractError {
+    /// The response is an error.
+    Error(ResponseError),
+    /// Failed to deserialize the result.
+    JsonError {
+        error: serde_json::Error,
+        /// The result that failed to deserialize.
+        result: serde_json::Value,
+    },
+}
+
+impl std::error::Error for ResponseExtractError {}
+impl fmt::Display for ResponseExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseExtractError::Error(error) => {
+                write!(f, "Error response {}: {}", error.code, error.message)
+            }
+            ResponseExtractError::JsonError { error, result } => {
+                write!(f, "Invalid response\n error: {error}\n result: {result}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestIdError {
+    /// The id is a string where a number was expected.
+    NotI32(RequestId),
+    /// The id does not fit in the bits left next to a server index.
+    InnerOutOfRange(i32),
+}
+
+impl std::error::Error for RequestIdError {}
+impl fmt::Display for RequestIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestIdError::NotI32(id) => write!(f, "RequestId {id} is not an i32"),
+            RequestIdError::InnerOutOfRange(inner) => {
+                write!(f, "RequestId {inner} does not fit next to a server index")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContextError {
+    /// The context does not name the language server it is meant for.
+    MissingLanguageServerId,
+}
+
+impl std::error::Error for ContextError {}
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextError::MissingLanguageServerId => write!(f, "No language-server-id in context"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// `start` or `end` is below zero.
+    Negative { start: i32, end: i32 },
+    /// `end` is before `start`.
+    Inverted { start: i32, end: i32 },
+}
+
+impl std::error::Error for RangeError {}
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeError::Negative { start, end } => {
+                write!(f, "Negative offset in range {start}..{end}")
+            }
+            RangeError::Inverted { start, end } => {
+                write!(f, "Inverted range {start}..{end}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A response carries both `result` and `error`.
+    ResultAndError(RequestId),
+    /// A response carries neither `result` nor `error`.
+    NoResultOrError(RequestId),
+    /// A request or notification has an empty method.
+    EmptyMethod,
+    /// Methods starting with `rpc.` are reserved by JSON-RPC.
+    ReservedMethod(String),
+}
+
+impl std::error::Error for ValidationError {}
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::ResultAndError(id) => {
+                write!(f, "Response {id} has both a result and an error")
+            }
+            ValidationError::NoResultOrError(id) => {
+                write!(f, "Response {id} has neither a result nor an error")
+            }
+            ValidationError::EmptyMethod => write!(f, "Empty method name"),
+            ValidationError::ReservedMethod(method) => {
+                write!(f, "Method '{method}' uses the reserved rpc. prefix")
+            }
+        }
+    }
+}
+
+/// The stream ended in the middle of a message, as opposed to between two
+/// messages, which reads as `Ok(None)`. Carried by `io::Error`s of kind
+/// `UnexpectedEof`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TruncatedMessage {
+    /// Inside the header block.
+    Headers { headers: String },
+    /// Before `Content-Length` bytes of body were read.
+    Body {
+        expected: usize,
+        read: usize,
+        headers: String,
+    },
+    /// Before all chunks of a chunked message arrived.
+    Chunk { index: usize, count: usize },
+}
+
+impl TruncatedMessage {
+    /// The truncation behind `err`, `None` for any other error.
+    pub fn of(err: &std::io::Error) -> Option<&TruncatedMessage> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::error::Error for TruncatedMessage {}
+impl fmt::Display for TruncatedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TruncatedMessage::Headers { headers } => {
+                write!(f, "stream ended inside the headers: {headers:?}")
+            }
+            TruncatedMessage::Body {
+                expected,
+                read,
+                headers,
+            } => write!(
+                f,
+                "truncated message body: Content-Length is {expected} bytes but only {read} bytes were read, headers: {headers:?}"
+            ),
+            TruncatedMessage::Chunk { index, count } => {
+                write!(f, "stream ended before chunk {index}/{count}")
+            }
+        }
+    }
+}
+
+impl From<TruncatedMessage> for std::io::Error {
+    fn from(value: TruncatedMessage) -> Self {
+        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, value)
+    }
+}
@@ -0,0 +1,130 @@
+//! Lossless logging of raw frames, for reproducing client bugs.
+//!
+//! Set `LSP_COPILOT_FRAME_LOG` to a file path to record every inbound and
+//! outbound frame (headers and body, before decompression) with a timestamp.
+//! The file is rotated to `<path>.1` once it grows past `MAX_LOG_BYTES`.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use log::warn;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+pub const FRAME_LOG_ENV: &str = "LSP_COPILOT_FRAME_LOG";
+const MAX_LOG_BYTES: u64 = 16 * 1024 * 1024;
+
+static FRAME_LOGGER: Lazy<Option<FrameLogger>> = Lazy::new(|| {
+    let path = std::env::var_os(FRAME_LOG_ENV)?;
+    match FrameLogger::open(PathBuf::from(path), MAX_LOG_BYTES) {
+        Ok(logger) => Some(logger),
+        Err(err) => {
+            warn!("Failed to open frame log: {}", err);
+            None
+        }
+    }
+});
+
+/// The logger configured through `LSP_COPILOT_FRAME_LOG`, if any.
+pub fn global() -> Option<&'static FrameLogger> {
+    FRAME_LOGGER.as_ref()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn marker(self) -> &'static str {
+        match self {
+            Direction::Inbound => "<",
+            Direction::Outbound => ">",
+        }
+    }
+}
+
+pub struct FrameLogger {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl FrameLogger {
+    pub fn new(out: impl Write + Send + 'static) -> FrameLogger {
+        FrameLogger {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Log to the file at `path`, rotating it once it exceeds `max_bytes`.
+    pub fn open(path: PathBuf, max_bytes: u64) -> io::Result<FrameLogger> {
+        Ok(FrameLogger::new(RotatingFile::open(path, max_bytes)?))
+    }
+
+    /// Record one frame. `headers` includes the blank line ending the header
+    /// block. Failing to log never fails the caller.
+    pub fn log(&self, direction: Direction, headers: &str, body: &[u8]) {
+        let mut entry = format!(
+            "{} {}\n{}",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+            direction.marker(),
+            headers
+        )
+        .into_bytes();
+        entry.extend_from_slice(body);
+        entry.push(b'\n');
+
+        let mut out = self.out.lock();
+        if let Err(err) = out.write_all(&entry).and_then(|_| out.flush()) {
+            warn!("Failed to write frame log: {}", err);
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<RotatingFile> {
+        let file = File::options().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            written,
+            max_bytes,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = OsString::from(self.path.as_os_str());
+        backup.push(".1");
+        fs::rename(&self.path, backup)?;
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
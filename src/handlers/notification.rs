@@ -6,7 +6,10 @@ use lsp_types::{
     MessageType, WillSaveTextDocumentParams,
 };
 
-use crate::{application::Application, document::DocumentId, lsp_ext, utils::get_activate_time};
+use crate::{
+    application::Application, document::DocumentId, lsp_ext, msg::RequestId,
+    utils::get_activate_time,
+};
 
 pub(crate) fn handle_did_open_text_document(
     app: &mut Application,
@@ -142,16 +145,22 @@ pub(crate) fn handle_cancel(
         .editor
         .document_by_uri(&lsp_types::Url::parse(uri).unwrap());
     if let Some(doc) = doc {
-        doc.language_servers().for_each(|ls| {
-            ls.cancel(lsp_types::CancelParams {
-                id: lsp_types::NumberOrString::Number(params.id.clone().into()),
-            })
-            .unwrap()
-        })
+        doc.language_servers()
+            .for_each(|ls| ls.cancel(cancel_params(&params.id)).unwrap())
     }
     Ok(())
 }
 
+/// `RequestId`'s `Display` quotes string ids, so map the id by its accessors.
+fn cancel_params(id: &RequestId) -> lsp_types::CancelParams {
+    let id = match (id.as_i32(), id.as_str()) {
+        (Some(id), _) => lsp_types::NumberOrString::Number(id),
+        (None, Some(id)) => lsp_types::NumberOrString::String(id.to_owned()),
+        (None, None) => unreachable!("a request id is a number or a string"),
+    };
+    lsp_types::CancelParams { id }
+}
+
 pub fn handle_did_focus_text_document(
     app: &mut Application,
     params: DidCloseTextDocumentParams,
@@ -165,3 +174,19 @@ pub fn handle_did_focus_text_document(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancel_params() {
+        let params = cancel_params(&RequestId::from("abc".to_string()));
+        assert_eq!(
+            params.id,
+            lsp_types::NumberOrString::String("abc".to_string())
+        );
+        let params = cancel_params(&RequestId::from(3));
+        assert_eq!(params.id, lsp_types::NumberOrString::Number(3));
+    }
+}
@@ -27,7 +27,12 @@ use crate::{
 
 pub fn create_error_response(id: &RequestId, message: String) -> Response {
     error!("result to response err {}", message);
-    Response::new_err(id.clone(), ErrorCode::InternalError as i32, message)
+    Response::new_err(id.clone(), ErrorCode::InternalError, message)
+}
+
+/// The server a context that names none is meant for.
+fn first_language_server_id(language_servers: &[Arc<Client>]) -> usize {
+    language_servers.first().map_or(0, |ls| ls.id())
 }
 
 async fn call_single_language_server<R>(
@@ -516,12 +521,13 @@ pub(crate) async fn handle_code_action_resolve(
     language_servers: Vec<Arc<Client>>,
 ) -> Result<Response> {
     if let Some(Context::CommonContext(context)) = &req.params.context {
+        let language_server_id = context.resolve(first_language_server_id(&language_servers));
         call_single_language_server::<lsp_types::request::CodeActionResolveRequest>(
             &req,
             code_action,
             &language_servers,
             None,
-            Some(context.language_server_id),
+            Some(language_server_id),
         )
         .await
         .and_then(|(action, _)| {
@@ -529,8 +535,8 @@ pub(crate) async fn handle_code_action_resolve(
                 req.id,
                 CodeActionOrCommandItem {
                     lsp_item: action.into(),
-                    language_server_id: context.language_server_id,
-                    language_server_name: format!("{:?}", context.language_server_id),
+                    language_server_id,
+                    language_server_name: format!("{:?}", language_server_id),
                 },
             ))
         })
@@ -567,9 +573,10 @@ pub(crate) async fn handle_execute_command(
     language_servers: Vec<Arc<Client>>,
 ) -> Result<Response> {
     if let Some(Context::CommonContext(context)) = &req.params.context {
+        let language_server_id = context.resolve(first_language_server_id(&language_servers));
         let language_server = language_servers
             .iter()
-            .find(|ls| ls.id() == context.language_server_id);
+            .find(|ls| ls.id() == language_server_id);
         if let Some(ls) = language_server {
             if ls.name() == "rust-analyzer" && params.command == "reloadWorkspace" {
                 return call_single_language_server::<lsp_ext::RustAnalyzerReloadWorkspace>(
@@ -581,7 +588,7 @@ pub(crate) async fn handle_execute_command(
                     (),
                     &language_servers,
                     None,
-                    Some(context.language_server_id),
+                    Some(language_server_id),
                 )
                 .await
                 .and_then(|_| Ok(Response::new_ok(req.id, "")));
@@ -592,7 +599,7 @@ pub(crate) async fn handle_execute_command(
             params,
             &language_servers,
             None,
-            Some(context.language_server_id),
+            Some(language_server_id),
         )
         .await
         .and_then(|_| Ok(Response::new_ok(req.id, "")))
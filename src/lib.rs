@@ -0,0 +1,32 @@
+mod application;
+pub mod args;
+pub mod bytecode;
+mod client;
+mod coalesce;
+mod code_action;
+mod completion_cache;
+pub mod config;
+pub mod connection;
+mod controller;
+mod diagnostics;
+mod dispatch;
+mod document;
+mod editor;
+mod error;
+mod frame_log;
+mod fuzzy;
+mod handlers;
+mod lifecycle;
+mod lsp;
+mod lsp_ext;
+pub mod main_loop;
+pub mod msg;
+mod partial_result;
+mod registry;
+mod req_queue;
+mod syntax;
+mod thread;
+mod throttle;
+mod transport;
+mod uri_rewrite;
+mod utils;
@@ -0,0 +1,97 @@
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use crate::msg::{Notification, Request};
+
+/// Where a session is in the `initialize` ... `shutdown`, `exit` lifecycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LifecycleState {
+    #[default]
+    Uninitialized,
+    Initialized,
+    ShutdownRequested,
+    /// `clean` if `shutdown` was requested before `exit`.
+    Exited {
+        clean: bool,
+    },
+}
+
+impl LifecycleState {
+    pub fn on_request(&mut self, req: &Request) {
+        if self.is_exited() {
+            return;
+        }
+        if req.is_initialize() && *self == LifecycleState::Uninitialized {
+            *self = LifecycleState::Initialized;
+        } else if req.is_shutdown() {
+            *self = LifecycleState::ShutdownRequested;
+        }
+    }
+
+    pub fn on_notification(&mut self, not: &Notification) {
+        if not.is_exit() && !self.is_exited() {
+            *self = LifecycleState::Exited {
+                clean: *self == LifecycleState::ShutdownRequested,
+            };
+        }
+    }
+
+    pub fn is_exited(&self) -> bool {
+        matches!(self, LifecycleState::Exited { .. })
+    }
+
+    /// The process exit code the spec asks for: 0 if `exit` followed
+    /// `shutdown`, 1 otherwise. `None` before `exit`.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            LifecycleState::Exited { clean: true } => Some(0),
+            LifecycleState::Exited { clean: false } => Some(1),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msg::RequestId;
+
+    fn request(id: i32, method: &str) -> Request {
+        Request::new(RequestId::from(id), method.to_string(), ())
+    }
+
+    fn exit() -> Notification {
+        Notification::new("exit".to_string(), ())
+    }
+
+    #[test]
+    fn test_clean_exit() {
+        let mut state = LifecycleState::default();
+        state.on_request(&request(1, "initialize"));
+        assert_eq!(state, LifecycleState::Initialized);
+        state.on_notification(&Notification::new("initialized".to_string(), ()));
+        state.on_request(&request(2, "textDocument/hover"));
+        assert_eq!(state, LifecycleState::Initialized);
+        state.on_request(&request(3, "shutdown"));
+        assert_eq!(state, LifecycleState::ShutdownRequested);
+        assert_eq!(state.exit_code(), None);
+        state.on_notification(&exit());
+        assert_eq!(state.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn test_premature_exit() {
+        let mut state = LifecycleState::default();
+        state.on_request(&request(1, "initialize"));
+        state.on_notification(&exit());
+        assert_eq!(state.exit_code(), Some(1));
+        // nothing changes after exit
+        state.on_request(&request(2, "shutdown"));
+        state.on_notification(&exit());
+        assert_eq!(state.exit_code(), Some(1));
+
+        let mut state = LifecycleState::default();
+        state.on_notification(&exit());
+        assert_eq!(state.exit_code(), Some(1));
+    }
+}
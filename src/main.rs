@@ -1,34 +1,11 @@
-mod application;
-mod args;
-mod bytecode;
-mod client;
-mod code_action;
-mod completion_cache;
-mod config;
-mod connection;
-mod controller;
-mod dispatch;
-mod document;
-mod editor;
-mod error;
-mod fuzzy;
-mod handlers;
-mod lsp;
-mod lsp_ext;
-mod main_loop;
-mod msg;
-mod registry;
-mod req_queue;
-mod syntax;
-mod thread;
-mod utils;
-
 use anyhow::{Context, Result};
-use args::Args;
-use config::{initialize_config_file, initialize_log_file, log_file};
 use log::{error, info};
-
-use crate::{connection::Connection, main_loop::main_loop};
+use lsp_copilot::{
+    args::Args,
+    config::{self, initialize_config_file, initialize_log_file, log_file},
+    connection::Connection,
+    main_loop::main_loop,
+};
 
 fn setup_logging(verbosity: u64) -> Result<()> {
     let mut base_config = fern::Dispatch::new();
@@ -1,10 +1,33 @@
 use core::fmt;
-use std::io::{self, BufRead, Write};
+use std::{
+    borrow::Cow,
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicI32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use log::{debug, warn};
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use log::{info, trace, warn};
+use lsp_types::notification::Notification as _;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{bytecode, error::ExtractError};
+use crate::{
+    bytecode,
+    error::{
+        ContextError, ExtractError, RangeError, RequestIdError, ResponseExtractError,
+        TruncatedMessage, ValidationError,
+    },
+    frame_log::{self, Direction, FrameLogger},
+    req_queue::CancellationRegistry,
+    uri_rewrite::UriRewriter,
+    utils::path,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
@@ -32,6 +55,85 @@ impl From<Notification> for Message {
     }
 }
 
+/// A one-line summary for logs, e.g. `Request#42 textDocument/hover uri=file:///a.rs`.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uri = match self {
+            Message::Request(req) => {
+                write!(f, "Request#{} {}", req.id, req.method)?;
+                &req.params.uri
+            }
+            Message::Notification(not) => {
+                write!(f, "Notification {}", not.method)?;
+                &not.params.uri
+            }
+            Message::Response(resp) => {
+                write!(f, "Response#{} ", resp.id)?;
+                return match &resp.error {
+                    None => write!(f, "ok"),
+                    Some(err) => write!(f, "err({}: {})", err.code, err.message),
+                };
+            }
+        };
+        match uri {
+            Some(uri) => write!(f, " uri={}", uri),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A message with its arrival order. Queues should be ordered by `seq`
+/// rather than by `RequestId`, which says nothing about arrival order.
+#[derive(Debug, Clone)]
+pub struct SequencedMessage {
+    pub seq: u64,
+    pub msg: Message,
+}
+
+impl PartialEq for SequencedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for SequencedMessage {}
+
+impl PartialOrd for SequencedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SequencedMessage {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.seq.cmp(&other.seq)
+    }
+}
+
+/// Numbers messages in the order they are read.
+#[derive(Debug, Default)]
+pub struct MessageSequencer {
+    next: AtomicU64,
+}
+
+impl MessageSequencer {
+    pub fn new() -> MessageSequencer {
+        MessageSequencer::default()
+    }
+
+    pub fn sequence(&self, msg: Message) -> SequencedMessage {
+        SequencedMessage {
+            seq: self.next.fetch_add(1, Ordering::Relaxed),
+            msg,
+        }
+    }
+
+    /// Read the next message and number it.
+    pub fn read(&self, r: &mut impl BufRead) -> io::Result<Option<SequencedMessage>> {
+        Ok(Message::read(r)?.map(|msg| self.sequence(msg)))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(untagged)]
 enum IdRepr {
@@ -39,6 +141,9 @@ enum IdRepr {
     String(String),
 }
 
+/// The derived `Ord` follows the declaration order of `IdRepr`, which happens
+/// to put numeric ids before string ids. Use `numeric_then_lexical_cmp` when
+/// code relies on that ordering.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(transparent)]
 pub struct RequestId(IdRepr);
@@ -54,14 +159,109 @@ impl From<String> for RequestId {
         RequestId(IdRepr::String(value))
     }
 }
-// Implement From<RequestId> for i32 TODO 确保都使用数字类型吧
-impl From<RequestId> for i32 {
-    fn from(request_id: RequestId) -> Self {
+
+impl TryFrom<RequestId> for i32 {
+    type Error = RequestIdError;
+
+    fn try_from(request_id: RequestId) -> Result<Self, Self::Error> {
         match request_id.0 {
-            IdRepr::I32(value) => value,
-            IdRepr::String(_) => panic!("RequestId does not contain an i32 value"),
+            IdRepr::I32(value) => Ok(value),
+            IdRepr::String(_) => Err(RequestIdError::NotI32(request_id)),
+        }
+    }
+}
+
+impl RequestId {
+    pub fn as_i32(&self) -> Option<i32> {
+        match &self.0 {
+            IdRepr::I32(value) => Some(*value),
+            IdRepr::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.0 {
+            IdRepr::I32(_) => None,
+            IdRepr::String(value) => Some(value),
+        }
+    }
+
+    /// The numeric value of the id, for code that cannot handle string ids
+    /// yet. Panics with `context` in the message if the id is a string.
+    pub fn expect_i32(&self, context: &str) -> i32 {
+        match self.as_i32() {
+            Some(value) => value,
+            None => panic!("{}: expected a numeric request id, got {}", context, self),
+        }
+    }
+
+    /// The largest id `with_server` can pack.
+    pub const MAX_INNER: i32 = (1 << 23) - 1;
+
+    /// Pack the index of a language server together with an id it picked, so
+    /// the ids of several servers do not collide.
+    ///
+    /// Packed ids are negative: the sign bit marks them, the next 8 bits hold
+    /// `server` and the low 23 bits `inner`, which must be in
+    /// `0..=RequestId::MAX_INNER`.
+    pub fn with_server(server: u8, inner: i32) -> Result<RequestId, RequestIdError> {
+        if !(0..=RequestId::MAX_INNER).contains(&inner) {
+            return Err(RequestIdError::InnerOutOfRange(inner));
+        }
+        let packed = (1 << 31) | (u32::from(server) << 23) | inner as u32;
+        Ok(RequestId::from(packed as i32))
+    }
+
+    /// The server index and inner id of an id made by `with_server`, `None`
+    /// for string ids and for non-negative ids, which are not packed.
+    pub fn server_and_inner(&self) -> Option<(u8, i32)> {
+        let value = self.as_i32().filter(|value| *value < 0)? as u32;
+        Some((
+            (value >> 23) as u8,
+            (value & RequestId::MAX_INNER as u32) as i32,
+        ))
+    }
+
+    /// Orders all numeric ids before string ids, numeric ids by value and
+    /// string ids lexically.
+    pub fn numeric_then_lexical_cmp(&self, other: &RequestId) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+
+        match (&self.0, &other.0) {
+            (IdRepr::I32(a), IdRepr::I32(b)) => a.cmp(b),
+            (IdRepr::String(a), IdRepr::String(b)) => a.cmp(b),
+            (IdRepr::I32(_), IdRepr::String(_)) => Ordering::Less,
+            (IdRepr::String(_), IdRepr::I32(_)) => Ordering::Greater,
+        }
+    }
+}
+
+/// Hands out ids for requests the proxy sends itself. Ids count up from
+/// `base`, wrapping back to it instead of overflowing past `i32::MAX`, so a
+/// `base` above the ids used by the client keeps them apart.
+#[derive(Debug)]
+pub struct IdAllocator {
+    base: i32,
+    next: AtomicI32,
+}
+
+impl IdAllocator {
+    pub fn new(base: i32) -> IdAllocator {
+        IdAllocator {
+            base,
+            next: AtomicI32::new(base),
         }
     }
+
+    pub fn next_id(&self) -> RequestId {
+        let id = self
+            .next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+                Some(id.checked_add(1).unwrap_or(self.base))
+            })
+            .unwrap();
+        RequestId::from(id)
+    }
 }
 
 impl fmt::Display for RequestId {
@@ -76,6 +276,68 @@ impl fmt::Display for RequestId {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct ProgressToken(IdRepr);
+
+impl From<i32> for ProgressToken {
+    fn from(value: i32) -> Self {
+        ProgressToken(IdRepr::I32(value))
+    }
+}
+
+impl From<String> for ProgressToken {
+    fn from(value: String) -> Self {
+        ProgressToken(IdRepr::String(value))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProgressParams {
+    pub token: ProgressToken,
+    pub value: serde_json::Value,
+}
+
+/// The `value` of `$/setTrace`. Unknown values are read as `Off`.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceLevel {
+    #[default]
+    Off,
+    Messages,
+    Verbose,
+}
+
+impl<'de> Deserialize<'de> for TraceLevel {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(match String::deserialize(deserializer)?.as_str() {
+            "messages" => TraceLevel::Messages,
+            "verbose" => TraceLevel::Verbose,
+            _ => TraceLevel::Off,
+        })
+    }
+}
+
+impl From<TraceLevel> for lsp_types::TraceValue {
+    fn from(value: TraceLevel) -> Self {
+        match value {
+            TraceLevel::Off => lsp_types::TraceValue::Off,
+            TraceLevel::Messages => lsp_types::TraceValue::Messages,
+            TraceLevel::Verbose => lsp_types::TraceValue::Verbose,
+        }
+    }
+}
+
+/// The params of a `telemetry/event` sent by the proxy itself.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TelemetryEvent {
+    pub event: String,
+    pub data: serde_json::Value,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CompletionContext {
     pub line: String,
@@ -88,6 +350,58 @@ pub struct CompletionContext {
     pub trigger_kind: lsp_types::CompletionTriggerKind,
 }
 
+impl CompletionContext {
+    /// Build a context from the text of the current line and the cursor's
+    /// character column, taking the word before the cursor as the prefix.
+    ///
+    /// `bounds_start` and `start_point` are columns within `line` rather than
+    /// buffer positions. A column past the end of the line is clamped to it.
+    pub fn from_line(
+        line: String,
+        column: usize,
+        trigger_kind: lsp_types::CompletionTriggerKind,
+    ) -> CompletionContext {
+        let before: Vec<char> = line.chars().take(column).collect();
+        let column = before.len();
+        let prefix_len = before
+            .iter()
+            .rev()
+            .take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '-')
+            .count();
+        let bounds_start = column - prefix_len;
+        CompletionContext {
+            prefix: before[bounds_start..].iter().collect(),
+            line,
+            start_point: column as i32,
+            bounds_start: bounds_start as i32,
+            trigger_kind,
+        }
+    }
+
+    /// Clear `prefix` unless completion was invoked by the user. For a
+    /// trigger character or a re-request of incomplete results, the word
+    /// before the cursor is not what the server should filter by.
+    pub fn normalize_for_trigger(&mut self) {
+        if self.trigger_kind != lsp_types::CompletionTriggerKind::INVOKED {
+            self.prefix.clear();
+        }
+    }
+}
+
+/// The cursor position of a position-dependent request such as hover or
+/// definition, so it can be read without deserializing the LSP params.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PositionContext {
+    pub line: u32,
+    pub character: u32,
+}
+
+impl From<PositionContext> for lsp_types::Position {
+    fn from(value: PositionContext) -> Self {
+        lsp_types::Position::new(value.line, value.character)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResolveContext {
     #[serde(rename = "language-server-id")]
@@ -96,43 +410,353 @@ pub struct ResolveContext {
     pub end: i32,
 }
 
+impl ResolveContext {
+    /// Check that `start..end` is a non-negative, non-inverted range.
+    /// Deserialization does not enforce this.
+    pub fn validate(&self) -> Result<(), RangeError> {
+        let (start, end) = (self.start, self.end);
+        if start < 0 || end < 0 {
+            return Err(RangeError::Negative { start, end });
+        }
+        if end < start {
+            return Err(RangeError::Inverted { start, end });
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WorkspaceContext {
     #[serde(rename = "workspace-root")]
     pub workspace_root: String,
+    #[serde(
+        rename = "additional-roots",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub additional_roots: Vec<String>,
+}
+
+impl WorkspaceContext {
+    /// `workspace_root` as an absolute path, with `~` expanded, `.`/`..`
+    /// components resolved and separators rebuilt for the platform. The
+    /// directory does not need to exist.
+    pub fn canonical_root(&self) -> io::Result<PathBuf> {
+        let root = path::expand_tilde(Path::new(&self.workspace_root));
+        let root = if root.is_relative() {
+            std::env::current_dir()?.join(root)
+        } else {
+            root.into_owned()
+        };
+        Ok(path::normalize(root))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CommonContext {
+    /// Often omitted when there is a single language server.
+    #[serde(
+        rename = "language-server-id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub language_server_id: Option<usize>,
+}
+
+impl CommonContext {
+    /// The named language server, or `default` if there is none.
+    pub fn resolve(&self, default: usize) -> usize {
+        self.language_server_id.unwrap_or(default)
+    }
+
+    /// The named language server, for requests that must name exactly one.
+    pub fn require(&self) -> Result<usize, ContextError> {
+        self.language_server_id
+            .ok_or(ContextError::MissingLanguageServerId)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignatureHelpContext {
+    #[serde(rename = "signature-trigger-character")]
+    pub signature_trigger_character: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiagnosticsContext {
     #[serde(rename = "language-server-id")]
     pub language_server_id: usize,
+    // the key must be present (possibly null), otherwise this is a `CommonContext`
+    #[serde(deserialize_with = "deserialize_present")]
+    pub version: Option<i32>,
 }
 
-// #[derive(Debug, Serialize, Deserialize, Clone)]
-// pub struct SignatureHelpContext {
-//     #[serde(rename = "signature-trigger-character")]
-//     pub signature_trigger_character: String,
-// }
+fn deserialize_present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer)
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// NOTE untagged variants are tried in order, so keep the variants with more
+// required fields before `CommonContext`, which has none and fits any object:
+// 1. `CompletionContext`: `line`, `prefix`, `startPoint`, `boundsStart`, `triggerKind`
+// 2. `PositionContext`: `line` (a number, unlike `CompletionContext`'s), `character`
+// 3. `ResolveContext`: `language-server-id`, `start`, `end`
+// 4. `SignatureHelpContext`: `signature-trigger-character`
+// 5. `DiagnosticsContext`: `language-server-id`, `version`
+// 6. `WorkspaceContext`: `workspace-root`
+// 7. `CommonContext`: optionally `language-server-id`
+// A `context-kind` field names the variant explicitly and skips the guessing.
+#[derive(Debug, Serialize, Clone)]
 #[serde(untagged)]
 pub enum Context {
     CompletionContext(CompletionContext),
+    PositionContext(PositionContext),
     ResolveContext(ResolveContext),
-    CommonContext(CommonContext),
+    SignatureHelpContext(SignatureHelpContext),
+    DiagnosticsContext(DiagnosticsContext),
     WorkspaceContext(WorkspaceContext),
-    // SignatureHelpContext(SignatureHelpContext),
+    CommonContext(CommonContext),
+}
+
+/// The optional field of a context naming its variant, e.g.
+/// `{"context-kind": "completion", ...}`.
+pub const CONTEXT_KIND: &str = "context-kind";
+
+impl Context {
+    /// The `context-kind` naming this variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Context::CompletionContext(_) => "completion",
+            Context::PositionContext(_) => "position",
+            Context::ResolveContext(_) => "resolve",
+            Context::SignatureHelpContext(_) => "signature-help",
+            Context::DiagnosticsContext(_) => "diagnostics",
+            Context::CommonContext(_) => "common",
+            Context::WorkspaceContext(_) => "workspace",
+        }
+    }
+
+    /// Deserialize `value` as the variant named `kind`. Unlike the untagged
+    /// resolution, a payload that does not fit that variant is an error.
+    pub fn from_tagged(kind: &str, value: &serde_json::Value) -> serde_json::Result<Context> {
+        use serde::de::Error as _;
+
+        Ok(match kind {
+            "completion" => Context::CompletionContext(Deserialize::deserialize(value)?),
+            "position" => Context::PositionContext(Deserialize::deserialize(value)?),
+            "resolve" => Context::ResolveContext(Deserialize::deserialize(value)?),
+            "signature-help" => Context::SignatureHelpContext(Deserialize::deserialize(value)?),
+            "diagnostics" => Context::DiagnosticsContext(Deserialize::deserialize(value)?),
+            "common" => Context::CommonContext(Deserialize::deserialize(value)?),
+            "workspace" => Context::WorkspaceContext(Deserialize::deserialize(value)?),
+            kind => {
+                return Err(serde_json::Error::custom(format!(
+                    "unknown {} `{}`",
+                    CONTEXT_KIND, kind
+                )))
+            }
+        })
+    }
+
+    /// The first variant `value` fits, in the order of the note above.
+    fn from_untagged(value: &serde_json::Value) -> Option<Context> {
+        fn fit<'a, T: Deserialize<'a>>(value: &'a serde_json::Value) -> Option<T> {
+            T::deserialize(value).ok()
+        }
+
+        None.or_else(|| fit(value).map(Context::CompletionContext))
+            .or_else(|| fit(value).map(Context::PositionContext))
+            .or_else(|| fit(value).map(Context::ResolveContext))
+            .or_else(|| fit(value).map(Context::SignatureHelpContext))
+            .or_else(|| fit(value).map(Context::DiagnosticsContext))
+            .or_else(|| fit(value).map(Context::WorkspaceContext))
+            .or_else(|| fit(value).map(Context::CommonContext))
+    }
+}
+
+impl<'de> Deserialize<'de> for Context {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        let kind = value
+            .as_object_mut()
+            .and_then(|object| object.remove(CONTEXT_KIND));
+        match kind {
+            Some(serde_json::Value::String(kind)) => {
+                Context::from_tagged(&kind, &value).map_err(D::Error::custom)
+            }
+            Some(kind) => Err(D::Error::custom(format!(
+                "{} must be a string, got {}",
+                CONTEXT_KIND, kind
+            ))),
+            None => Context::from_untagged(&value).ok_or_else(|| {
+                D::Error::custom("data did not match any variant of untagged enum Context")
+            }),
+        }
+    }
+}
+
+/// Several contexts of different kinds, for requests that need more than one,
+/// e.g. which server and which workspace root. Serialized as an object keyed
+/// by `context-kind`: `{"common": {...}, "workspace": {...}}`.
+#[derive(Debug, Clone, Default)]
+pub struct ContextSet {
+    contexts: Vec<Context>,
+}
+
+impl ContextSet {
+    pub fn new() -> ContextSet {
+        ContextSet::default()
+    }
+
+    /// Add `context`, replacing the one of the same kind.
+    pub fn insert(&mut self, context: Context) {
+        self.contexts.retain(|old| old.kind() != context.kind());
+        self.contexts.push(context);
+    }
+
+    pub fn get(&self, kind: &str) -> Option<&Context> {
+        self.contexts.iter().find(|context| context.kind() == kind)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Context> {
+        self.contexts.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    pub fn common(&self) -> Option<&CommonContext> {
+        match self.get("common")? {
+            Context::CommonContext(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    pub fn workspace(&self) -> Option<&WorkspaceContext> {
+        match self.get("workspace")? {
+            Context::WorkspaceContext(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    pub fn completion(&self) -> Option<&CompletionContext> {
+        match self.get("completion")? {
+            Context::CompletionContext(context) => Some(context),
+            _ => None,
+        }
+    }
+}
+
+impl From<Context> for ContextSet {
+    fn from(context: Context) -> Self {
+        ContextSet {
+            contexts: vec![context],
+        }
+    }
+}
+
+impl Serialize for ContextSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.contexts.len()))?;
+        for context in &self.contexts {
+            map.serialize_entry(context.kind(), context)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ContextSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        let map = serde_json::Map::<String, serde_json::Value>::deserialize(deserializer)?;
+        let mut set = ContextSet::new();
+        for (kind, value) in &map {
+            set.insert(Context::from_tagged(kind, value).map_err(D::Error::custom)?);
+        }
+        Ok(set)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Params {
     pub uri: Option<String>,
     pub context: Option<Context>,
+    /// Contexts of several kinds, sent next to or instead of `context`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contexts: Option<ContextSet>,
     #[serde(default = "serde_json::Value::default")]
     #[serde(skip_serializing_if = "serde_json::Value::is_null")]
     pub params: serde_json::Value,
+    /// Unknown top-level fields, kept so they survive re-serialization.
+    #[serde(flatten, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Params {
+    /// The params to forward to a language server, without the proxy-specific
+    /// `uri`, `context` and extra fields.
+    pub fn into_lsp_params(self) -> serde_json::Value {
+        self.params
+    }
+
+    pub fn proxy_context(&self) -> Option<&Context> {
+        self.context.as_ref()
+    }
+
+    /// `contexts` together with `context`, which wins over a context of the
+    /// same kind in `contexts`.
+    pub fn context_set(&self) -> ContextSet {
+        let mut set = self.contexts.clone().unwrap_or_default();
+        if let Some(context) = &self.context {
+            set.insert(context.clone());
+        }
+        set
+    }
+
+    /// Rewrite `uri` and the uris inside the LSP params, such as
+    /// `textDocument.uri`. Pass `rewriter.reversed()` for messages going to
+    /// the client.
+    pub fn rewrite_uri(&mut self, rewriter: &UriRewriter) {
+        if let Some(uri) = &mut self.uri {
+            rewriter.rewrite_in_place(uri);
+        }
+        rewriter.rewrite_value(&mut self.params);
+    }
+
+    /// Parse `uri`, `None` if there is none.
+    pub fn uri_as_url(&self) -> Option<Result<lsp_types::Url, UrlParseError>> {
+        self.uri.as_deref().map(lsp_types::Url::parse)
+    }
+
+    /// The local path of a `file://` uri. Drive-letter uris such as
+    /// `file:///C:/foo` become `C:\foo` on Windows, elsewhere `/C:/foo` is an
+    /// ordinary unix path.
+    pub fn file_path(&self) -> Option<PathBuf> {
+        let url = self.uri_as_url()?.ok()?;
+        if url.scheme() != "file" {
+            return None;
+        }
+        url.to_file_path().ok()
+    }
 }
 
+pub type UrlParseError = <lsp_types::Url as std::str::FromStr>::Err;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Request {
     pub id: RequestId,
@@ -146,12 +770,22 @@ pub struct Response {
     // to decode the request's id. Ignore this special case and
     // just die horribly
     pub id: RequestId,
+    /// `Some(Value::Null)` for `"result": null`, `None` if there is no result.
+    #[serde(default, deserialize_with = "deserialize_result")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<ResponseError>,
 }
 
+/// A `result` that is present, even as `null`, is `Some`.
+fn deserialize_result<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResponseError {
     pub code: i32,
@@ -160,264 +794,4609 @@ pub struct ResponseError {
     pub data: Option<serde_json::Value>,
 }
 
+impl ResponseError {
+    /// The `code` as a known `ErrorCode`, `None` if it is not one.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        ErrorCode::from_i32(self.code)
+    }
+
+    /// Deserialize `data` into `T`, `None` if there is no data.
+    pub fn data_as<T: DeserializeOwned>(&self) -> Option<Result<T, serde_json::Error>> {
+        self.data.as_ref().map(T::deserialize)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Notification {
     pub method: String,
     pub params: Params,
 }
 
-impl Message {
-    pub fn read(r: &mut impl BufRead) -> io::Result<Option<Message>> {
-        Message::_read(r)
-    }
+macro_rules! known_methods {
+    ($($variant:ident => $method:literal,)*) => {
+        /// LSP methods the proxy knows about.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum KnownMethod {
+            $($variant,)*
+        }
 
-    fn _read(r: &mut dyn BufRead) -> io::Result<Option<Message>> {
-        let text = match read_msg_text(r)? {
-            None => return Ok(None),
-            Some(text) => text,
-        };
-        let msg = serde_json::from_str(&text)?;
-        Ok(Some(msg))
-    }
+        impl KnownMethod {
+            pub fn from_method(method: &str) -> Option<KnownMethod> {
+                match method {
+                    $($method => Some(KnownMethod::$variant),)*
+                    _ => None,
+                }
+            }
 
-    pub fn write(self, w: &mut impl Write) -> io::Result<()> {
-        self._write(w)
-    }
-    pub fn _write(self, w: &mut impl Write) -> io::Result<()> {
-        #[derive(Serialize)]
-        struct JsonRpc {
-            jsonrpc: &'static str,
-            #[serde(flatten)]
-            msg: Message,
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(KnownMethod::$variant => $method,)*
+                }
+            }
         }
-        let json_val = serde_json::to_value(&JsonRpc {
-            jsonrpc: "2.0",
-            msg: self.clone(),
-        })?;
+    };
+}
 
-        let text = serde_json::to_string(&JsonRpc {
-            jsonrpc: "2.0",
-            msg: self,
-        })?;
+known_methods! {
+    Initialize => "initialize",
+    Initialized => "initialized",
+    Shutdown => "shutdown",
+    Exit => "exit",
+    CancelRequest => "$/cancelRequest",
+    Progress => "$/progress",
+    DidOpen => "textDocument/didOpen",
+    DidChange => "textDocument/didChange",
+    DidSave => "textDocument/didSave",
+    DidClose => "textDocument/didClose",
+    PublishDiagnostics => "textDocument/publishDiagnostics",
+    Completion => "textDocument/completion",
+    CompletionResolve => "completionItem/resolve",
+    Hover => "textDocument/hover",
+    SignatureHelp => "textDocument/signatureHelp",
+    Definition => "textDocument/definition",
+    References => "textDocument/references",
+    CodeAction => "textDocument/codeAction",
+    Formatting => "textDocument/formatting",
+    Rename => "textDocument/rename",
+    ExecuteCommand => "workspace/executeCommand",
+    DidChangeConfiguration => "workspace/didChangeConfiguration",
+}
 
-        // debug!("> {}", text);
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LspMethod {
+    Known(KnownMethod),
+    Other(String),
+}
 
-        match bytecode::generate_bytecode_repl(&json_val, bytecode::BytecodeOptions::default()) {
-            Ok(bytecode_str) => {
-                // debug!(
-                //     "server->client: json {} byteds, converted to bytecode, {} bytes",
-                //     text.len(),
-                //     bytecode_str.len()
-                // );
-                write_msg_text(w, &bytecode_str)
-            }
-            Err(err) => {
-                warn!("Failed to convert json to bytecode: {}", err);
-                write_msg_text(w, &text)
-            }
+impl LspMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LspMethod::Known(method) => method.as_str(),
+            LspMethod::Other(method) => method,
         }
     }
 }
 
-impl Response {
-    pub fn new_ok<R: Serialize>(id: RequestId, result: R) -> Response {
-        Response {
-            id,
-            result: Some(serde_json::to_value(result).unwrap()),
-            error: None,
+impl From<&str> for LspMethod {
+    fn from(method: &str) -> LspMethod {
+        match KnownMethod::from_method(method) {
+            Some(method) => LspMethod::Known(method),
+            None => LspMethod::Other(method.to_string()),
         }
     }
-    pub fn new_err(id: RequestId, code: i32, message: String) -> Response {
-        let error = ResponseError {
-            code,
-            message,
-            data: None,
-        };
-        Response {
-            id,
-            result: None,
-            error: Some(error),
+}
+
+impl Message {
+    /// The method of a request or notification, `None` for a response.
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Message::Request(req) => Some(&req.method),
+            Message::Response(_) => None,
+            Message::Notification(not) => Some(&not.method),
         }
     }
-}
 
-#[allow(dead_code)]
-impl Request {
+    pub fn is_request(&self) -> bool {
+        matches!(self, Message::Request(_))
+    }
+
+    pub fn is_response(&self) -> bool {
+        matches!(self, Message::Response(_))
+    }
+
+    pub fn is_notification(&self) -> bool {
+        matches!(self, Message::Notification(_))
+    }
+
+    pub fn as_request(&self) -> Option<&Request> {
+        match self {
+            Message::Request(req) => Some(req),
+            _ => None,
+        }
+    }
+
+    pub fn as_response(&self) -> Option<&Response> {
+        match self {
+            Message::Response(resp) => Some(resp),
+            _ => None,
+        }
+    }
+
+    pub fn as_notification(&self) -> Option<&Notification> {
+        match self {
+            Message::Notification(not) => Some(not),
+            _ => None,
+        }
+    }
+
+    /// Convert a JSON value into a message, classifying it by its `id`,
+    /// `method`, `result` and `error` fields. The error names the field
+    /// that was missing when the value fits no kind of message.
+    pub fn from_value(v: serde_json::Value) -> Result<Message, ExtractError<serde_json::Value>> {
+        fn invalid(
+            method: String,
+            params: serde_json::Value,
+            message: &str,
+        ) -> ExtractError<serde_json::Value> {
+            ExtractError::JsonError {
+                method,
+                error: serde::de::Error::custom(message),
+                params,
+            }
+        }
+
+        let Some(obj) = v.as_object() else {
+            return Err(invalid(String::new(), v, "message is not an object"));
+        };
+        let method = obj
+            .get("method")
+            .and_then(|m| m.as_str())
+            .map(str::to_owned);
+        let has_id = obj.contains_key("id");
+        let is_response = obj.contains_key("result") || obj.contains_key("error");
+        let result = match (&method, has_id) {
+            (Some(_), true) => serde_json::from_value::<Request>(v.clone()).map(Message::from),
+            (Some(_), false) => {
+                serde_json::from_value::<Notification>(v.clone()).map(Message::from)
+            }
+            (None, true) if is_response => {
+                serde_json::from_value::<Response>(v.clone()).map(Message::from)
+            }
+            (None, true) => {
+                return Err(invalid(
+                    String::new(),
+                    v,
+                    "no `method` and no `result` or `error`",
+                ))
+            }
+            (None, false) => return Err(invalid(String::new(), v, "no `id` and no `method`")),
+        };
+        result.map_err(|error| ExtractError::JsonError {
+            method: method.unwrap_or_default(),
+            error,
+            params: v,
+        })
+    }
+
+    /// The method of a request or notification as an `LspMethod`, `None`
+    /// for a response.
+    pub fn lsp_method(&self) -> Option<LspMethod> {
+        self.method().map(LspMethod::from)
+    }
+
+    /// The id of a request or response, `None` for a notification.
+    pub fn id(&self) -> Option<&RequestId> {
+        match self {
+            Message::Request(req) => Some(&req.id),
+            Message::Response(resp) => Some(&resp.id),
+            Message::Notification(_) => None,
+        }
+    }
+
+    pub fn read(r: &mut impl BufRead) -> io::Result<Option<Message>> {
+        Message::_read(r, None, &mut Vec::new(), &ReadOptions::default())
+    }
+
+    /// Like `read`, but rejects a message whose `Content-Length` exceeds
+    /// `max_bytes` before allocating its body.
+    pub fn read_with_limit(r: &mut impl BufRead, max_bytes: usize) -> io::Result<Option<Message>> {
+        let opts = ReadOptions {
+            max_content_length: Some(max_bytes),
+            ..ReadOptions::default()
+        };
+        Message::_read(r, None, &mut Vec::new(), &opts)
+    }
+
+    pub fn read_with_options(
+        r: &mut impl BufRead,
+        opts: &ReadOptions,
+    ) -> io::Result<Option<Message>> {
+        Message::_read(r, None, &mut Vec::new(), opts)
+    }
+
+    pub fn read_with_metrics(
+        r: &mut impl BufRead,
+        metrics: &dyn MessageMetrics,
+    ) -> io::Result<Option<Message>> {
+        Message::_read(r, Some(metrics), &mut Vec::new(), &ReadOptions::default())
+    }
+
+    /// Like `read`, but also returns the body as received, for replaying it
+    /// byte for byte. A compressed body is returned decompressed.
+    pub fn read_with_raw(r: &mut impl BufRead) -> io::Result<Option<(Message, String)>> {
+        let mut buf = Vec::new();
+        let text =
+            match read_msg_text_into(r, frame_log::global(), &mut buf, &ReadOptions::default())? {
+                None => return Ok(None),
+                Some(text) => text,
+            };
+        let msg = Message::parse_text(text, None, &ReadOptions::default())?;
+        Ok(Some((msg, text.to_owned())))
+    }
+
+    /// Like `read`, but reads the body into `buf`, which is cleared first.
+    /// Reusing one buffer saves an allocation per message.
+    pub fn read_with_buffer(
+        r: &mut impl BufRead,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<Option<Message>> {
+        Message::_read(r, None, buf, &ReadOptions::default())
+    }
+
+    fn _read(
+        r: &mut dyn BufRead,
+        metrics: Option<&dyn MessageMetrics>,
+        buf: &mut Vec<u8>,
+        opts: &ReadOptions,
+    ) -> io::Result<Option<Message>> {
+        let text = match timed(metrics, MessagePhase::ReadText, || {
+            read_msg_text_into(r, frame_log::global(), buf, opts)
+        })? {
+            None => return Ok(None),
+            Some(text) => text,
+        };
+        let msg = Message::parse_text(text, metrics, opts)?;
+        if opts.validate {
+            msg.validate().map_err(invalid_data)?;
+        }
+        Ok(Some(msg))
+    }
+
+    /// Check the structural rules of JSON-RPC the types do not enforce: a
+    /// response has exactly one of `result` and `error`, and a method is
+    /// neither empty nor reserved.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let method = match self {
+            Message::Request(req) => &req.method,
+            Message::Notification(not) => &not.method,
+            Message::Response(resp) => {
+                return match (&resp.result, &resp.error) {
+                    (Some(_), Some(_)) => Err(ValidationError::ResultAndError(resp.id.clone())),
+                    (None, None) => Err(ValidationError::NoResultOrError(resp.id.clone())),
+                    _ => Ok(()),
+                };
+            }
+        };
+        if method.is_empty() {
+            return Err(ValidationError::EmptyMethod);
+        }
+        if method.starts_with("rpc.") {
+            return Err(ValidationError::ReservedMethod(method.clone()));
+        }
+        Ok(())
+    }
+
+    /// Like `read`, but a frame that is not a valid message is skipped
+    /// instead of failing the read, be it for its body or for headers such as
+    /// an empty or oversized `Content-Length` or an unsupported charset.
+    /// Frames are length-delimited, so the stream stays aligned after a
+    /// skipped frame. Broken framing is still an error.
+    pub fn read_recoverable(r: &mut impl BufRead) -> io::Result<ReadOutcome> {
+        let opts = ReadOptions::default();
+        let header = match read_header_block(r, &opts)? {
+            None => return Ok(ReadOutcome::Eof),
+            Some(header) => header,
+        };
+        if let Err(err) = header.check_length(opts.max_content_length()) {
+            skip_msg_body(r, &header)?;
+            warn!("skipping malformed message: {}", err);
+            return Ok(ReadOutcome::Skipped {
+                reason: err.to_string(),
+            });
+        }
+        let mut buf = Vec::new();
+        read_msg_frames_from(r, frame_log::global(), &mut buf, &opts, &header)?;
+        match decode_msg_text(&header, &mut buf)
+            .and_then(|text| Message::parse_text(text, None, &opts))
+        {
+            Ok(msg) => Ok(ReadOutcome::Message(msg)),
+            Err(err) => {
+                warn!("skipping malformed message: {}", err);
+                Ok(ReadOutcome::Skipped {
+                    reason: err.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Read the next message without decoding it, for forwarding it with
+    /// `write_raw`. Returns its method, empty for a response, found without
+    /// building the message.
+    pub fn read_raw(r: &mut impl BufRead) -> io::Result<Option<(String, RawFrame)>> {
+        let mut body = Vec::new();
+        let header =
+            match read_msg_frames(r, frame_log::global(), &mut body, &ReadOptions::default())? {
+                None => return Ok(None),
+                Some(header) => header,
+            };
+        let method = match header.encoding {
+            Some(_) => {
+                let mut decoded = body.clone();
+                peek_method(decode_msg_text(&header, &mut decoded)?)?
+            }
+            None => peek_method(std::str::from_utf8(&body).map_err(invalid_data)?)?,
+        };
+        let headers = match header.chunk {
+            // the reassembled body goes out as one frame
+            Some(_) => match &header.encoding {
+                Some(encoding) => format!(
+                    "Content-Length: {}\r\nContent-Encoding: {}\r\n\r\n",
+                    body.len(),
+                    encoding
+                ),
+                None => format!("Content-Length: {}\r\n\r\n", body.len()),
+            },
+            None => header.headers,
+        };
+        Ok(Some((method, RawFrame { headers, body })))
+    }
+
+    /// Forward a frame from `read_raw` as it was received.
+    pub fn write_raw(w: &mut impl Write, frame: &RawFrame) -> io::Result<()> {
+        write_frame(w, &frame.headers, &frame.body, frame_log::global())
+    }
+
+    /// Deserialize the body of a frame, JSON or, if `opts` accepts it,
+    /// bytecode.
+    fn parse_text(
+        text: &str,
+        metrics: Option<&dyn MessageMetrics>,
+        opts: &ReadOptions,
+    ) -> io::Result<Message> {
+        let msg: IncomingMessage = timed(metrics, MessagePhase::Deserialize, || {
+            if !opts.accept_bytecode || text.trim_start().starts_with(['{', '[']) {
+                return serde_json::from_str(text).map_err(io::Error::from);
+            }
+            // The client may send its messages as bytecode too.
+            let value = bytecode::parse_bytecode_repl(text)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            serde_json::from_value(value).map_err(io::Error::from)
+        })?;
+        let msg = msg.into_message()?;
+        msg.log_recv();
+        Ok(msg)
+    }
+
+    /// Log the kind, method and id of a received message, leaving the full
+    /// body to the trace level.
+    fn log_recv(&self) {
+        let kind = match self {
+            Message::Request(_) => "request",
+            Message::Response(_) => "response",
+            Message::Notification(_) => "notification",
+        };
+        let method = self
+            .lsp_method()
+            .map(|method| format!(" method={}", method.as_str()))
+            .unwrap_or_default();
+        let id = self
+            .id()
+            .map(|id| format!(" id={}", id))
+            .unwrap_or_default();
+        info!("recv {}{}{}", kind, method, id);
+    }
+
+    /// Read a frame that may hold either a single message or a JSON-RPC batch
+    /// (an array of messages). A single message is returned as a one-element batch.
+    pub fn read_batch(r: &mut impl BufRead) -> io::Result<Option<Vec<Message>>> {
+        Message::read_batch_with_options(r, &ReadOptions::default())
+    }
+
+    /// Like `read_batch`. With `accept_bytecode`, reads the bytecode elements
+    /// `write_batch` writes.
+    pub fn read_batch_with_options(
+        r: &mut impl BufRead,
+        opts: &ReadOptions,
+    ) -> io::Result<Option<Vec<Message>>> {
+        let text = match read_msg_text(r)? {
+            None => return Ok(None),
+            Some(text) => text,
+        };
+        let Some(mut rest) = text.trim_start().strip_prefix('[') else {
+            return Message::parse_text(&text, None, opts).map(|msg| Some(vec![msg]));
+        };
+        // `write_batch` writes each element as bytecode or JSON, so the
+        // elements are read one by one rather than as a JSON array.
+        let mut batch = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(end) = rest.strip_prefix(']') {
+                if !end.trim().is_empty() {
+                    return Err(invalid_data("trailing data after batch"));
+                }
+                return Ok(Some(batch));
+            }
+            if !batch.is_empty() {
+                rest = rest
+                    .strip_prefix(',')
+                    .ok_or_else(|| invalid_data("expected `,` between batch elements"))?
+                    .trim_start();
+            }
+            let (value, len) = if opts.accept_bytecode && rest.starts_with('#') {
+                bytecode::parse_bytecode_prefix(rest)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            } else {
+                let mut values =
+                    serde_json::Deserializer::from_str(rest).into_iter::<serde_json::Value>();
+                let value = values
+                    .next()
+                    .ok_or_else(|| invalid_data("unterminated batch"))??;
+                (value, values.byte_offset())
+            };
+            rest = &rest[len..];
+            let msg = serde_json::from_value::<IncomingMessage>(value)?.into_message()?;
+            msg.log_recv();
+            batch.push(msg);
+        }
+    }
+
+    pub fn write(self, w: &mut impl Write) -> io::Result<()> {
+        self._write(w, &WriteOptions::default(), None)
+    }
+
+    pub fn write_with_options(self, w: &mut impl Write, options: &WriteOptions) -> io::Result<()> {
+        self._write(w, options, None)
+    }
+
+    pub fn write_with_metrics(
+        self,
+        w: &mut impl Write,
+        options: &WriteOptions,
+        metrics: &dyn MessageMetrics,
+    ) -> io::Result<()> {
+        self._write(w, options, Some(metrics))
+    }
+
+    /// Write all messages as one framed JSON-RPC batch, `[a,b,...]`. Each
+    /// element is encoded as bytecode when possible, otherwise it is written
+    /// as JSON text. A body like `[#[...],{...}]` is neither JSON nor
+    /// bytecode, only `read_batch` with `accept_bytecode` can parse it.
+    pub fn write_batch(batch: Vec<Message>, w: &mut impl Write) -> io::Result<()> {
+        Message::write_batch_with_options(batch, w, &WriteOptions::default())
+    }
+
+    pub fn write_batch_with_options(
+        batch: Vec<Message>,
+        w: &mut impl Write,
+        options: &WriteOptions,
+    ) -> io::Result<()> {
+        let mut elements = Vec::new();
+        for msg in batch {
+            if let Some(json_val) = msg.outgoing_json(options)? {
+                elements.push(encode_json_value(&json_val, &options.bytecode, None)?);
+            }
+        }
+        // a batch must not be empty
+        if elements.is_empty() {
+            return Ok(());
+        }
+        let body = format!("[{}]", elements.join(","));
+        write_msg_text(w, &body, options.content_encoding)
+    }
+    pub fn _write(
+        self,
+        w: &mut impl Write,
+        options: &WriteOptions,
+        metrics: Option<&dyn MessageMetrics>,
+    ) -> io::Result<()> {
+        let json_val = match self.outgoing_json(options)? {
+            None => return Ok(()),
+            Some(json_val) => json_val,
+        };
+
+        // debug!("> {}", json_val);
+
+        write_json_value(w, &json_val, options, metrics)
+    }
+
+    /// The message as it is sent with `options`, or `None` if the
+    /// notification filter drops it.
+    fn outgoing_json(self, options: &WriteOptions) -> io::Result<Option<serde_json::Value>> {
+        if let (Message::Notification(not), Some(filter)) = (&self, &options.notification_filter) {
+            if !filter.permits(&not.method) {
+                return Ok(None);
+            }
+        }
+        let mut json_val = self.into_json()?;
+        if !options.emit_jsonrpc_field {
+            if let Some(obj) = json_val.as_object_mut() {
+                obj.remove("jsonrpc");
+            }
+        }
+        Ok(Some(json_val))
+    }
+
+    /// Write a message whose `result` or `params` is the `len` bytes of JSON
+    /// read from `body`, streaming them to `w` without holding them in
+    /// memory. Always JSON, never bytecode.
+    ///
+    /// With an `id` and no `method` this is a response and `body` its result,
+    /// otherwise a request or notification and `body` its params.
+    pub fn write_from_reader(
+        id: Option<RequestId>,
+        method: Option<&str>,
+        body: impl Read,
+        len: usize,
+        w: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut prefix = String::from(r#"{"jsonrpc":"2.0""#);
+        if let Some(id) = &id {
+            prefix.push_str(&format!(r#","id":{}"#, serde_json::to_string(id)?));
+        }
+        match (&id, method) {
+            (_, Some(method)) => {
+                prefix.push_str(&format!(r#","method":{}"#, serde_json::to_string(method)?));
+                prefix.push_str(r#","params":"#);
+            }
+            (Some(_), None) => prefix.push_str(r#","result":"#),
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a streamed message needs an id or a method",
+                ))
+            }
+        }
+        let suffix = "}";
+
+        write!(
+            w,
+            "Content-Length: {}\r\n\r\n{}",
+            prefix.len() + len + suffix.len(),
+            prefix
+        )?;
+        let copied = io::copy(&mut body.take(len as u64), w)?;
+        if copied < len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("streamed body ended after {} of {} bytes", copied, len),
+            ));
+        }
+        w.write_all(suffix.as_bytes())?;
+        w.flush()
+    }
+
+    /// Like `write`, but split bodies larger than `chunk_size` bytes into
+    /// several frames carrying an `X-Chunk: i/n` header. `read` reassembles
+    /// them.
+    pub fn write_chunked(self, w: &mut impl Write, chunk_size: usize) -> io::Result<()> {
+        self.write_chunked_with_options(w, chunk_size, &WriteOptions::default())
+    }
+
+    /// Like `write_chunked`. A compressed body is split after compression,
+    /// and only the first frame carries the `Content-Encoding`.
+    pub fn write_chunked_with_options(
+        self,
+        w: &mut impl Write,
+        chunk_size: usize,
+        options: &WriteOptions,
+    ) -> io::Result<()> {
+        let json_val = match self.outgoing_json(options)? {
+            None => return Ok(()),
+            Some(json_val) => json_val,
+        };
+        let body = encode_json_value(&json_val, &options.bytecode, None)?;
+        write_msg_chunks(w, &body, options.content_encoding, chunk_size)
+    }
+
+    /// The message as a JSON-RPC value. The JSON text is only produced from
+    /// it when needed, see `encode_json_value`.
+    fn into_json(self) -> io::Result<serde_json::Value> {
+        #[derive(Serialize)]
+        struct JsonRpc {
+            jsonrpc: &'static str,
+            #[serde(flatten)]
+            msg: Message,
+        }
+        Ok(serde_json::to_value(JsonRpc {
+            jsonrpc: "2.0",
+            msg: self,
+        })?)
+    }
+}
+
+/// A frame read by `Message::read_raw`, with its header block and body as
+/// they were received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    headers: String,
+    body: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn headers(&self) -> &str {
+        &self.headers
+    }
+
+    /// The body, still compressed if it was sent with a `Content-Encoding`.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+/// The method of a JSON or bytecode message body, empty for a response.
+/// JSON is only scanned for the `method` field.
+fn peek_method(text: &str) -> io::Result<String> {
+    #[derive(Deserialize)]
+    struct Peek<'a> {
+        #[serde(borrow)]
+        method: Option<Cow<'a, str>>,
+    }
+
+    if text.trim_start().starts_with('{') {
+        let peek: Peek = serde_json::from_str(text)?;
+        return Ok(peek.method.unwrap_or_default().into_owned());
+    }
+    let value = bytecode::parse_bytecode_repl(text)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(value
+        .get("method")
+        .and_then(|method| method.as_str())
+        .unwrap_or_default()
+        .to_owned())
+}
+
+/// The result of `Message::read_recoverable`.
+#[derive(Debug)]
+pub enum ReadOutcome {
+    Message(Message),
+    /// A frame was read, but its body is not a valid message.
+    Skipped {
+        reason: String,
+    },
+    Eof,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    pub framing: Framing,
+    /// Reject bodies larger than this, in bytes. Never above the built-in
+    /// limit of 512 MiB.
+    pub max_content_length: Option<usize>,
+    /// Reject header lines longer than this, in bytes. Defaults to 64 KiB.
+    pub max_header_bytes: Option<usize>,
+    /// Reject messages that break the JSON-RPC rules, see `Message::validate`.
+    pub validate: bool,
+    /// Decode bodies that are not JSON as Emacs bytecode, for clients that
+    /// send their messages the way they receive them.
+    pub accept_bytecode: bool,
+}
+
+impl ReadOptions {
+    fn max_content_length(&self) -> usize {
+        self.max_content_length
+            .map_or(MAX_CONTENT_LENGTH, |max| max.min(MAX_CONTENT_LENGTH))
+    }
+
+    fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes.unwrap_or(MAX_HEADER_BYTES)
+    }
+}
+
+/// How strictly header lines are checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Header lines end with `\r\n`, as the base protocol requires.
+    #[default]
+    Strict,
+    /// Also accept header lines ending with a bare `\n`, for naive clients.
+    Lenient,
+}
+
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    pub bytecode: bytecode::BytecodeOptions,
+    /// Compress the body and announce it with a `Content-Encoding` header.
+    pub content_encoding: Option<ContentEncoding>,
+    /// Drop notifications the filter does not permit instead of writing them.
+    pub notification_filter: Option<NotificationFilter>,
+    /// Include `"jsonrpc": "2.0"`; set to false for clients that do not expect it.
+    pub emit_jsonrpc_field: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            bytecode: bytecode::BytecodeOptions::default(),
+            content_encoding: None,
+            notification_filter: None,
+            emit_jsonrpc_field: true,
+        }
+    }
+}
+
+/// Decides which notifications are forwarded to Emacs by method.
+///
+/// A pattern is a method name, or a prefix followed by `*` such as
+/// `$/*`. With an allow list only matching methods pass; the deny list is
+/// applied after it.
+#[derive(Clone, Debug, Default)]
+pub struct NotificationFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl NotificationFilter {
+    pub fn new() -> NotificationFilter {
+        NotificationFilter::default()
+    }
+
+    pub fn allow(mut self, pattern: &str) -> NotificationFilter {
+        self.allow.push(pattern.to_string());
+        self
+    }
+
+    pub fn deny(mut self, pattern: &str) -> NotificationFilter {
+        self.deny.push(pattern.to_string());
+        self
+    }
+
+    pub fn permits(&self, method: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => method == pattern,
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    /// zlib-wrapped deflate, as in HTTP.
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn from_name(name: &str) -> Option<ContentEncoding> {
+        if name.eq_ignore_ascii_case("gzip") {
+            Some(ContentEncoding::Gzip)
+        } else if name.eq_ignore_ascii_case("deflate") {
+            Some(ContentEncoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress `body`, refusing to inflate past `MAX_CONTENT_LENGTH`.
+    fn decode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let limit = MAX_CONTENT_LENGTH as u64 + 1;
+        let mut decoded = Vec::new();
+        match self {
+            ContentEncoding::Gzip => GzDecoder::new(body).take(limit).read_to_end(&mut decoded)?,
+            ContentEncoding::Deflate => ZlibDecoder::new(body)
+                .take(limit)
+                .read_to_end(&mut decoded)?,
+        };
+        if decoded.len() > MAX_CONTENT_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} body inflates past the limit of {} bytes",
+                    self.name(),
+                    MAX_CONTENT_LENGTH
+                ),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessagePhase {
+    /// `read_msg_text`
+    ReadText,
+    /// `serde_json::from_str`
+    Deserialize,
+    /// `bytecode::generate_bytecode_repl`
+    Bytecode,
+    /// `write_msg_text`
+    WriteText,
+}
+
+/// Receives the duration of each phase of reading or writing a message.
+pub trait MessageMetrics {
+    fn record(&self, phase: MessagePhase, duration: Duration);
+}
+
+/// Run `f`, reporting its duration to `metrics` if a sink is installed.
+fn timed<T>(metrics: Option<&dyn MessageMetrics>, phase: MessagePhase, f: impl FnOnce() -> T) -> T {
+    match metrics {
+        None => f(),
+        Some(metrics) => {
+            let start = Instant::now();
+            let result = f();
+            metrics.record(phase, start.elapsed());
+            result
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Message {
+    pub async fn read_async(
+        r: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    ) -> io::Result<Option<Message>> {
+        Message::read_with_options_async(r, &ReadOptions::default()).await
+    }
+
+    /// Like `read_with_options`. Framing, limits and decoding are the same as
+    /// for the sync reader, only the reads are awaited.
+    pub async fn read_with_options_async(
+        r: &mut (impl tokio::io::AsyncBufRead + Unpin),
+        opts: &ReadOptions,
+    ) -> io::Result<Option<Message>> {
+        let mut buf = Vec::new();
+        let header = match read_msg_frames_async(r, frame_log::global(), &mut buf, opts).await? {
+            None => return Ok(None),
+            Some(header) => header,
+        };
+        let msg = Message::parse_text(decode_msg_text(&header, &mut buf)?, None, opts)?;
+        if opts.validate {
+            msg.validate().map_err(invalid_data)?;
+        }
+        Ok(Some(msg))
+    }
+
+    /// The message is framed in memory with the sync writer, then written out
+    /// in one go.
+    pub async fn write_async(
+        self,
+        w: &mut (impl tokio::io::AsyncWrite + Unpin),
+        options: &WriteOptions,
+    ) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut buf = Vec::new();
+        self._write(&mut buf, options, None)?;
+        w.write_all(&buf).await?;
+        w.flush().await
+    }
+}
+
+/// A message as read from the wire, keeping the `jsonrpc` version for validation.
+#[derive(Deserialize)]
+struct IncomingMessage {
+    jsonrpc: Option<String>,
+    #[serde(flatten)]
+    msg: Message,
+}
+
+impl IncomingMessage {
+    fn into_message(self) -> io::Result<Message> {
+        match self.jsonrpc.as_deref() {
+            Some("2.0") => {}
+            Some(version) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported jsonrpc version: {:?}", version),
+                ))
+            }
+            // tolerated for backwards compatibility
+            None => warn!("message without jsonrpc version: {:?}", self.msg.method()),
+        }
+        Ok(self.msg)
+    }
+}
+
+/// Write `json_val` as bytecode, falling back to the JSON `text` unless the
+/// options are strict.
+fn write_json_value(
+    w: &mut dyn Write,
+    json_val: &serde_json::Value,
+    options: &WriteOptions,
+    metrics: Option<&dyn MessageMetrics>,
+) -> io::Result<()> {
+    let body = encode_json_value(json_val, &options.bytecode, metrics)?;
+    timed(metrics, MessagePhase::WriteText, || {
+        write_msg_text(w, &body, options.content_encoding)
+    })
+}
+
+/// The body to send for `json_val`: its bytecode, or its JSON text if it is
+/// shorter than `min_bytecode_bytes`, or if the conversion fails and `options`
+/// allows falling back.
+fn encode_json_value(
+    json_val: &serde_json::Value,
+    options: &bytecode::BytecodeOptions,
+    metrics: Option<&dyn MessageMetrics>,
+) -> io::Result<String> {
+    if let Some(text) = options
+        .min_bytecode_bytes
+        .and_then(|min| json_text_below(json_val, min))
+    {
+        return Ok(text);
+    }
+    let bytecode = timed(metrics, MessagePhase::Bytecode, || {
+        bytecode::generate_bytecode_repl(json_val, options.clone())
+    });
+    match bytecode {
+        Ok(bytecode_str) => {
+            // debug!(
+            //     "server->client: json {} byteds, converted to bytecode, {} bytes",
+            //     text.len(),
+            //     bytecode_str.len()
+            // );
+            Ok(bytecode_str)
+        }
+        Err(err) => {
+            let message = format!("Failed to convert json to bytecode: {}", err);
+            if let Some(hook) = &options.on_fallback {
+                (hook.0)(&message);
+            }
+            if options.strict {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            warn!("{}", message);
+            Ok(serde_json::to_string(json_val)?)
+        }
+    }
+}
+
+/// The JSON text of `json_val` if it is shorter than `limit` bytes. Gives up
+/// as soon as the limit is reached, so large values are not serialized twice.
+fn json_text_below(json_val: &serde_json::Value, limit: usize) -> Option<String> {
+    struct Bounded {
+        buf: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Bounded {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if self.buf.len() + data.len() >= self.limit {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.buf.extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut out = Bounded {
+        buf: Vec::new(),
+        limit,
+    };
+    serde_json::to_writer(&mut out, json_val).ok()?;
+    // serde_json only writes valid UTF-8
+    String::from_utf8(out.buf).ok()
+}
+
+impl Response {
+    pub fn new_ok<R: Serialize>(id: RequestId, result: R) -> Response {
+        Response {
+            id,
+            result: Some(serde_json::to_value(result).unwrap()),
+            error: None,
+        }
+    }
+
+    /// The reply to a `workspace/configuration` request, one value per
+    /// requested item, `null` for unknown sections.
+    pub fn configuration(id: RequestId, values: Vec<serde_json::Value>) -> Response {
+        Response::new_ok(id, values)
+    }
+
+    /// Deserialize the result as the result of the LSP method `R`, or return
+    /// the error. `"result": null` is `Some(Value::Null)`, a response with
+    /// neither result nor error is taken as a `null` result too.
+    pub fn extract_lsp<R: lsp_types::request::Request>(
+        self,
+    ) -> Result<R::Result, ResponseExtractError> {
+        if let Some(error) = self.error {
+            return Err(ResponseExtractError::Error(error));
+        }
+        let result = self.result.unwrap_or_default();
+        match R::Result::deserialize(&result) {
+            Ok(result) => Ok(result),
+            Err(error) => Err(ResponseExtractError::JsonError { error, result }),
+        }
+    }
+
+    /// Whether the client canceled the request this responds to. The id is
+    /// removed from `reg`, so only the first check reports it.
+    pub fn is_for_canceled(&self, reg: &CancellationRegistry) -> bool {
+        reg.take(&self.id)
+    }
+
+    /// Rewrite the uris in the result, such as the `uri` of locations.
+    pub fn rewrite_uri(&mut self, rewriter: &UriRewriter) {
+        if let Some(result) = &mut self.result {
+            rewriter.rewrite_value(result);
+        }
+    }
+
+    pub fn new_err(id: RequestId, code: ErrorCode, message: String) -> Response {
+        Response::new_err_code(id, code as i32, message)
+    }
+
+    /// Like `new_err`, with `data` serialized into the error's `data`.
+    pub fn new_err_with_data<D: Serialize>(
+        id: RequestId,
+        code: ErrorCode,
+        message: String,
+        data: D,
+    ) -> Response {
+        let mut response = Response::new_err(id, code, message);
+        if let Some(error) = &mut response.error {
+            error.data = Some(serde_json::to_value(data).unwrap());
+        }
+        response
+    }
+
+    /// Like `new_err`, for codes not covered by `ErrorCode`.
+    pub fn new_err_code(id: RequestId, code: i32, message: String) -> Response {
+        let error = ResponseError {
+            code,
+            message,
+            data: None,
+        };
+        Response {
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Request {
+    /// A successful response to this request, echoing its id.
+    pub fn ok_response<R: Serialize>(&self, result: R) -> Response {
+        Response::new_ok(self.id.clone(), result)
+    }
+
+    /// An error response to this request, echoing its id.
+    pub fn err_response(&self, code: ErrorCode, message: String) -> Response {
+        Response::new_err(self.id.clone(), code, message)
+    }
+
     pub fn new<P: Serialize>(id: RequestId, method: String, params: P) -> Request {
+        Request::new_with(id, method, None, None, params)
+    }
+
+    /// Whether this is a request a language server sends to the client, per
+    /// `SERVER_INITIATED_METHODS`.
+    pub fn is_server_initiated(&self) -> bool {
+        self.is_server_initiated_with(SERVER_INITIATED_METHODS)
+    }
+
+    /// Like `is_server_initiated`, with a custom set of methods.
+    pub fn is_server_initiated_with(&self, methods: &[&str]) -> bool {
+        methods.contains(&self.method.as_str())
+    }
+
+    /// The uri and position of any request shaped like
+    /// `TextDocumentPositionParams`, such as hover, definition or completion.
+    pub fn text_document_position(&self) -> Option<(String, lsp_types::Position)> {
+        let params = &self.params.params;
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let position = lsp_types::Position::deserialize(params.get("position")?).ok()?;
+        Some((uri.to_string(), position))
+    }
+
+    /// The command and arguments of a `workspace/executeCommand`, with no
+    /// arguments if they are omitted.
+    pub fn execute_command(&self) -> Option<(String, Vec<serde_json::Value>)> {
+        if !self.is_execute_command() {
+            return None;
+        }
+        let params = &self.params.params;
+        let command = params.get("command")?.as_str()?;
+        let arguments = match params.get("arguments") {
+            Some(serde_json::Value::Array(arguments)) => arguments.clone(),
+            _ => Vec::new(),
+        };
+        Some((command.to_string(), arguments))
+    }
+
+    /// The items of a `workspace/configuration` request, in the order the
+    /// values of the response must follow.
+    pub fn configuration_items(&self) -> Option<Vec<lsp_types::ConfigurationItem>> {
+        use lsp_types::request::{Request as _, WorkspaceConfiguration};
+
+        if self.method != WorkspaceConfiguration::METHOD {
+            return None;
+        }
+        let params = lsp_types::ConfigurationParams::deserialize(&self.params.params).ok()?;
+        Some(params.items)
+    }
+
+    /// A request for the LSP method `R`.
+    pub fn from_lsp<R: lsp_types::request::Request>(id: RequestId, params: R::Params) -> Request {
+        Request::new(id, R::METHOD.to_string(), params)
+    }
+
+    pub fn new_with<P: Serialize>(
+        id: RequestId,
+        method: String,
+        uri: Option<String>,
+        context: Option<Context>,
+        params: P,
+    ) -> Request {
         Request {
             id,
             method,
             params: Params {
-                uri: None,
-                context: None,
+                uri,
+                context,
+                contexts: None,
+                params: serde_json::to_value(params).unwrap(),
+                extra: serde_json::Map::new(),
+            },
+        }
+    }
+
+    pub fn uri(&self) -> Option<String> {
+        self.params.uri.to_owned()
+    }
+
+    pub fn extract<P: DeserializeOwned>(
+        self,
+        method: &str,
+    ) -> Result<(RequestId, P), ExtractError<Request>> {
+        if self.method != method {
+            return Err(ExtractError::MethodMismatch(self));
+        }
+        match P::deserialize(&self.params.params) {
+            Ok(params) => Ok((self.id, params)),
+            Err(error) => Err(ExtractError::JsonError {
+                method: self.method,
+                error,
+                params: self.params.params,
+            }),
+        }
+    }
+
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.method == KnownMethod::Shutdown.as_str()
+    }
+    pub(crate) fn is_initialize(&self) -> bool {
+        self.method == KnownMethod::Initialize.as_str()
+    }
+    pub(crate) fn is_execute_command(&self) -> bool {
+        self.method == KnownMethod::ExecuteCommand.as_str()
+    }
+}
+
+#[cfg(feature = "lsp-server-interop")]
+impl From<RequestId> for lsp_server::RequestId {
+    fn from(value: RequestId) -> Self {
+        match value.0 {
+            IdRepr::I32(value) => value.into(),
+            IdRepr::String(value) => value.into(),
+        }
+    }
+}
+
+/// `lsp_server::RequestId` has no accessors, its serialized form is the same
+/// as ours.
+#[cfg(feature = "lsp-server-interop")]
+impl From<lsp_server::RequestId> for RequestId {
+    fn from(value: lsp_server::RequestId) -> Self {
+        serde_json::to_value(value)
+            .and_then(serde_json::from_value)
+            .expect("lsp_server::RequestId is an i32 or a string")
+    }
+}
+
+/// Only the inner `params` are kept: `lsp_server::Request::params` holds the
+/// LSP params directly, without the `uri`, `context` and extra fields Emacs
+/// sends around them.
+#[cfg(feature = "lsp-server-interop")]
+impl From<Request> for lsp_server::Request {
+    fn from(value: Request) -> Self {
+        lsp_server::Request {
+            id: value.id.into(),
+            method: value.method,
+            params: value.params.into_lsp_params(),
+        }
+    }
+}
+
+/// The params become the inner `params`, with no `uri` or `context`.
+#[cfg(feature = "lsp-server-interop")]
+impl From<lsp_server::Request> for Request {
+    fn from(value: lsp_server::Request) -> Self {
+        Request::new(value.id.into(), value.method, value.params)
+    }
+}
+
+#[allow(dead_code)]
+impl Notification {
+    pub fn new(method: String, params: impl Serialize) -> Notification {
+        Notification::new_with(method, None, None, params)
+    }
+
+    /// A notification for the LSP method `N`.
+    pub fn from_lsp<N: lsp_types::notification::Notification>(params: N::Params) -> Notification {
+        Notification::new(N::METHOD.to_string(), params)
+    }
+
+    pub fn new_with(
+        method: String,
+        uri: Option<String>,
+        context: Option<Context>,
+        params: impl Serialize,
+    ) -> Notification {
+        Notification {
+            method,
+            params: Params {
+                uri,
+                context,
+                contexts: None,
                 params: serde_json::to_value(params).unwrap(),
+                extra: serde_json::Map::new(),
+            },
+        }
+    }
+
+    pub fn uri(&self) -> Option<String> {
+        self.params.uri.to_owned()
+    }
+
+    /// Build a `$/cancelRequest` notification for the request `id`.
+    pub fn cancel(id: RequestId) -> Notification {
+        Notification::new(
+            lsp_types::notification::Cancel::METHOD.to_string(),
+            serde_json::json!({ "id": id }),
+        )
+    }
+
+    /// The id of the canceled request if this is a `$/cancelRequest` notification.
+    pub fn canceled_id(&self) -> Option<RequestId> {
+        if self.method != lsp_types::notification::Cancel::METHOD {
+            return None;
+        }
+        serde_json::from_value(self.params.params.get("id")?.clone()).ok()
+    }
+
+    /// Whether a `textDocument/didChange` carries incremental changes, i.e.
+    /// any of its `contentChanges` has a `range`. `None` for other methods.
+    pub fn is_incremental_change(&self) -> Option<bool> {
+        if self.method != KnownMethod::DidChange.as_str() {
+            return None;
+        }
+        let incremental = match self.params.params.get("contentChanges") {
+            Some(serde_json::Value::Array(changes)) => {
+                changes.iter().any(|change| change.get("range").is_some())
+            }
+            _ => false,
+        };
+        Some(incremental)
+    }
+
+    /// The uri and language id of a `textDocument/didOpen`.
+    pub fn opened_document(&self) -> Option<(String, String)> {
+        if self.method != KnownMethod::DidOpen.as_str() {
+            return None;
+        }
+        let document = self.params.params.get("textDocument")?;
+        let uri = document.get("uri")?.as_str()?;
+        let language_id = document.get("languageId")?.as_str()?;
+        Some((uri.to_string(), language_id.to_string()))
+    }
+
+    /// The uri of a `textDocument/didClose`.
+    pub fn closed_document(&self) -> Option<String> {
+        if self.method != KnownMethod::DidClose.as_str() {
+            return None;
+        }
+        let uri = self.params.params.get("textDocument")?.get("uri")?;
+        uri.as_str().map(str::to_owned)
+    }
+
+    /// The `textDocument.version` of a `textDocument/didChange` or
+    /// `textDocument/didOpen`, for detecting out-of-order or duplicate
+    /// changes. `None` for other methods.
+    pub fn document_version(&self) -> Option<i32> {
+        if self.method != KnownMethod::DidChange.as_str()
+            && self.method != KnownMethod::DidOpen.as_str()
+        {
+            return None;
+        }
+        let version = self.params.params.get("textDocument")?.get("version")?;
+        version.as_i64()?.try_into().ok()
+    }
+
+    pub fn progress(token: ProgressToken, value: impl Serialize) -> Notification {
+        Notification::new(
+            lsp_types::notification::Progress::METHOD.to_string(),
+            ProgressParams {
+                token,
+                value: serde_json::to_value(value).unwrap(),
+            },
+        )
+    }
+
+    pub fn as_progress(&self) -> Option<ProgressParams> {
+        if self.method != lsp_types::notification::Progress::METHOD {
+            return None;
+        }
+        serde_json::from_value(self.params.params.clone()).ok()
+    }
+
+    /// A `telemetry/event` reporting what the proxy did, e.g. dropped messages.
+    pub fn telemetry(event: &str, data: impl Serialize) -> Notification {
+        Notification::new(
+            lsp_types::notification::TelemetryEvent::METHOD.to_string(),
+            TelemetryEvent {
+                event: event.to_string(),
+                data: serde_json::to_value(data).unwrap(),
+            },
+        )
+    }
+
+    pub fn set_trace(level: TraceLevel) -> Notification {
+        Notification::new(
+            lsp_types::notification::SetTrace::METHOD.to_string(),
+            serde_json::json!({ "value": level }),
+        )
+    }
+
+    pub fn as_set_trace(&self) -> Option<TraceLevel> {
+        if self.method != lsp_types::notification::SetTrace::METHOD {
+            return None;
+        }
+        let value = self.params.params.get("value")?;
+        Some(TraceLevel::deserialize(value).unwrap_or_default())
+    }
+
+    pub fn as_telemetry(&self) -> Option<TelemetryEvent> {
+        if self.method != lsp_types::notification::TelemetryEvent::METHOD {
+            return None;
+        }
+        serde_json::from_value(self.params.params.clone()).ok()
+    }
+
+    pub fn extract<P: DeserializeOwned>(
+        self,
+        method: &str,
+    ) -> Result<P, ExtractError<Notification>> {
+        if self.method != method {
+            return Err(ExtractError::MethodMismatch(self));
+        }
+        match P::deserialize(&self.params.params) {
+            Ok(params) => Ok(params),
+            Err(error) => Err(ExtractError::JsonError {
+                method: self.method,
+                error,
+                params: self.params.params,
+            }),
+        }
+    }
+    pub(crate) fn is_exit(&self) -> bool {
+        self.method == KnownMethod::Exit.as_str()
+    }
+    /// `initialize` is always a request, the lifecycle notification is
+    /// `initialized`.
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.method == KnownMethod::Initialized.as_str()
+    }
+}
+
+/// The requests of the spec sent from server to client.
+pub const SERVER_INITIATED_METHODS: &[&str] = &[
+    "client/registerCapability",
+    "client/unregisterCapability",
+    "window/showMessageRequest",
+    "window/showDocument",
+    "window/workDoneProgress/create",
+    "workspace/applyEdit",
+    "workspace/configuration",
+    "workspace/workspaceFolders",
+    "workspace/codeLens/refresh",
+    "workspace/diagnostic/refresh",
+    "workspace/inlayHint/refresh",
+    "workspace/inlineValue/refresh",
+    "workspace/semanticTokens/refresh",
+];
+
+/// Bodies larger than this are rejected before the buffer is allocated.
+const MAX_CONTENT_LENGTH: usize = 512 * 1024 * 1024;
+
+/// Header lines longer than this are rejected, so a peer that never sends a
+/// line terminator cannot grow the buffer without bound.
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+macro_rules! invalid_data {
+    ($($tt:tt)*) => (invalid_data(format!($($tt)*)))
+}
+
+fn read_msg_text(inp: &mut dyn BufRead) -> io::Result<Option<String>> {
+    read_msg_text_logged(inp, frame_log::global())
+}
+
+fn read_msg_text_logged(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+) -> io::Result<Option<String>> {
+    let mut body = Vec::new();
+    let header = match read_msg_frames(inp, logger, &mut body, &ReadOptions::default())? {
+        None => return Ok(None),
+        Some(header) => header,
+    };
+    header.finish(body).map(Some)
+}
+
+/// Like `read_msg_text`, but reads into `buf` and borrows the text from it.
+fn read_msg_text_into<'a>(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    buf: &'a mut Vec<u8>,
+    opts: &ReadOptions,
+) -> io::Result<Option<&'a str>> {
+    let header = match read_msg_frames(inp, logger, buf, opts)? {
+        None => return Ok(None),
+        Some(header) => header,
+    };
+    decode_msg_text(&header, buf).map(Some)
+}
+
+/// Decode the body read with `header` in place and borrow it as text.
+fn decode_msg_text<'a>(header: &MsgHeader, buf: &'a mut Vec<u8>) -> io::Result<&'a str> {
+    header.decode_body(buf)?;
+    let text = std::str::from_utf8(buf).map_err(invalid_data)?;
+    trace!("< {}", text);
+    Ok(text)
+}
+
+/// Read the raw body of the next message into `body`, reassembling it if it
+/// was sent in chunks.
+fn read_msg_frames(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    body: &mut Vec<u8>,
+    opts: &ReadOptions,
+) -> io::Result<Option<MsgHeader>> {
+    let header = match read_headers(inp, opts)? {
+        None => return Ok(None),
+        Some(header) => header,
+    };
+    read_msg_frames_from(inp, logger, body, opts, &header)?;
+    Ok(Some(header))
+}
+
+/// Read the body of the frame `header` was read from into `body`, and the
+/// chunks following it if it is the first of several.
+fn read_msg_frames_from(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    body: &mut Vec<u8>,
+    opts: &ReadOptions,
+    header: &MsgHeader,
+) -> io::Result<()> {
+    read_frame_body(inp, logger, body, header)?;
+    if let Some((_, count)) = header.first_chunk()? {
+        for index in 2..=count {
+            let next = read_frame(inp, logger, opts)?;
+            append_chunk(body, next, index, count, opts.max_content_length())?;
+        }
+    }
+    Ok(())
+}
+
+/// Read a single frame, without decoding its body.
+fn read_frame(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    opts: &ReadOptions,
+) -> io::Result<Option<(MsgHeader, Vec<u8>)>> {
+    let mut buf = Vec::new();
+    Ok(read_frame_into(inp, logger, &mut buf, opts)?.map(|header| (header, buf)))
+}
+
+/// Read a single frame, replacing the contents of `buf` with its body.
+fn read_frame_into(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    buf: &mut Vec<u8>,
+    opts: &ReadOptions,
+) -> io::Result<Option<MsgHeader>> {
+    let header = match read_headers(inp, opts)? {
+        None => return Ok(None),
+        Some(header) => header,
+    };
+    read_frame_body(inp, logger, buf, &header)?;
+    Ok(Some(header))
+}
+
+/// Replace the contents of `buf` with the body of the frame `header` was
+/// read from.
+fn read_frame_body(
+    inp: &mut dyn BufRead,
+    logger: Option<&FrameLogger>,
+    buf: &mut Vec<u8>,
+    header: &MsgHeader,
+) -> io::Result<()> {
+    read_msg_body(inp, buf, header.content_length, &header.headers)?;
+    if let Some(logger) = logger {
+        logger.log(Direction::Inbound, &header.headers, buf);
+    }
+    Ok(())
+}
+
+/// Read and parse the header block of the next frame, leaving the body
+/// unread. A missing, empty or oversized `Content-Length` fails here.
+fn read_headers(inp: &mut dyn BufRead, opts: &ReadOptions) -> io::Result<Option<MsgHeader>> {
+    let header = read_header_block(inp, opts)?;
+    if let Some(header) = &header {
+        header.check_length(opts.max_content_length())?;
+    }
+    Ok(header)
+}
+
+/// Like `read_headers`, but leaves checking the `Content-Length` against
+/// the limits to the caller.
+fn read_header_block(inp: &mut dyn BufRead, opts: &ReadOptions) -> io::Result<Option<MsgHeader>> {
+    let mut header = HeaderParser::new(opts);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let read = inp
+            .take(opts.max_header_bytes() as u64 + 1)
+            .read_line(&mut line)?;
+        if read == 0 && header.headers.is_empty() {
+            return Ok(None);
+        }
+        if let Some(header) = header.push_read_line(&line, opts)? {
+            return Ok(Some(header));
+        }
+    }
+}
+
+/// Fail if `line`, read with a limit of one byte past `limit`, was cut off
+/// before its terminator, by the limit or by the end of the stream.
+fn check_header_line(line: &str, limit: usize, headers: &str) -> io::Result<()> {
+    if line.ends_with('\n') {
+        return Ok(());
+    }
+    if line.len() > limit {
+        return Err(invalid_data!(
+            "header line exceeds the limit of {} bytes",
+            limit
+        ));
+    }
+    Err(TruncatedMessage::Headers {
+        headers: format!("{}{}", headers, line),
+    }
+    .into())
+}
+
+/// Append chunk `index` of `count` to the body reassembled so far.
+fn append_chunk(
+    body: &mut Vec<u8>,
+    next: Option<(MsgHeader, Vec<u8>)>,
+    index: usize,
+    count: usize,
+    limit: usize,
+) -> io::Result<()> {
+    let (header, chunk) = next.ok_or(TruncatedMessage::Chunk { index, count })?;
+    if header.chunk != Some((index, count)) {
+        return Err(invalid_data!(
+            "expected chunk {}/{}, headers: {:?}",
+            index,
+            count,
+            header.headers
+        ));
+    }
+    if body.len() + chunk.len() > limit {
+        return Err(invalid_data!(
+            "chunked message exceeds the limit of {} bytes",
+            limit
+        ));
+    }
+    body.extend_from_slice(&chunk);
+    Ok(())
+}
+
+/// The header block of a frame, parsed before its body is read so the
+/// declared size can be checked first.
+struct MsgHeader {
+    content_length: usize,
+    content_type: Option<String>,
+    encoding: Option<String>,
+    /// The raw header block, kept for error messages.
+    headers: String,
+    /// `X-Chunk: i/n`, one-based.
+    chunk: Option<(usize, usize)>,
+}
+
+/// Header state of a message being read, shared by the sync and async readers.
+#[derive(Default)]
+struct HeaderParser {
+    size: Option<usize>,
+    content_type: Option<String>,
+    encoding: Option<String>,
+    /// The raw header block, kept for error messages.
+    headers: String,
+    /// Lowercased names of the headers seen so far.
+    names: Vec<String>,
+    /// `X-Chunk: i/n`, one-based.
+    chunk: Option<(usize, usize)>,
+    framing: Framing,
+}
+
+impl HeaderParser {
+    fn new(opts: &ReadOptions) -> Self {
+        HeaderParser {
+            framing: opts.framing,
+            ..HeaderParser::default()
+        }
+    }
+
+    /// Feed one line as read with a limit of one byte past
+    /// `opts.max_header_bytes`. Returns the headers once the header block
+    /// ends, with the `Content-Length` not yet checked against the limits.
+    fn push_read_line(&mut self, line: &str, opts: &ReadOptions) -> io::Result<Option<MsgHeader>> {
+        check_header_line(line, opts.max_header_bytes(), &self.headers)?;
+        if !self.push_line(line)? {
+            return Ok(None);
+        }
+        std::mem::take(self).into_header().map(Some)
+    }
+
+    /// Feed one header line including its line terminator. Returns `true` once
+    /// the empty line ending the header block is seen.
+    fn push_line(&mut self, line: &str) -> io::Result<bool> {
+        if cfg!(debug_assertions) && self.headers.is_empty() {
+            check_frame_start(line);
+        }
+        self.headers.push_str(line);
+        let line = match line.strip_suffix("\r\n") {
+            Some(line) => line,
+            None => match line.strip_suffix('\n') {
+                Some(line) if self.framing == Framing::Lenient => {
+                    static WARNED: std::sync::Once = std::sync::Once::new();
+                    WARNED.call_once(|| warn!("accepting header lines ending with a bare \\n"));
+                    line
+                }
+                _ => return Err(invalid_data!("malformed header: {:?}", line)),
+            },
+        };
+        if line.is_empty() {
+            return Ok(true);
+        }
+        let mut parts = line.splitn(2, ": ");
+        let header_name = parts.next().unwrap();
+        let header_value = parts
+            .next()
+            .ok_or_else(|| invalid_data!("malformed header: {:?}", line))?;
+        let name = header_name.to_ascii_lowercase();
+        if self.names.contains(&name) {
+            return Err(invalid_data!("duplicate header: {:?}", header_name));
+        }
+        self.names.push(name);
+        if header_name.eq_ignore_ascii_case("Content-Length") {
+            self.size = Some(header_value.parse::<usize>().map_err(invalid_data)?);
+        } else if header_name.eq_ignore_ascii_case("Content-Type") {
+            self.content_type = Some(header_value.to_owned());
+        } else if header_name.eq_ignore_ascii_case("Content-Encoding") {
+            self.encoding = Some(header_value.to_owned());
+        } else if header_name.eq_ignore_ascii_case("X-Chunk") {
+            let chunk = header_value
+                .split_once('/')
+                .and_then(|(i, n)| Some((i.parse().ok()?, n.parse().ok()?)))
+                .filter(|&(i, n)| 1 <= i && i <= n)
+                .ok_or_else(|| invalid_data!("malformed X-Chunk: {:?}", header_value))?;
+            self.chunk = Some(chunk);
+        }
+        Ok(false)
+    }
+
+    /// The parsed headers. Without a `Content-Length` the frame cannot be
+    /// delimited.
+    fn into_header(self) -> io::Result<MsgHeader> {
+        let size: usize = self
+            .size
+            .ok_or_else(|| invalid_data!("no Content-Length"))?;
+        Ok(MsgHeader {
+            content_length: size,
+            content_type: self.content_type,
+            encoding: self.encoding,
+            headers: self.headers,
+            chunk: self.chunk,
+        })
+    }
+}
+
+impl MsgHeader {
+    /// Check the declared body size against `limit`.
+    fn check_length(&self, limit: usize) -> io::Result<()> {
+        // An empty body is never a valid JSON-RPC message, catch it before
+        // it turns into a confusing parse error.
+        if self.content_length == 0 {
+            return Err(invalid_data!(
+                "empty message body, headers: {:?}",
+                self.headers
+            ));
+        }
+        if self.content_length > limit {
+            return Err(invalid_data!(
+                "Content-Length {} exceeds the limit of {} bytes, headers: {:?}",
+                self.content_length,
+                limit,
+                self.headers
+            ));
+        }
+        Ok(())
+    }
+
+    /// The `X-Chunk` of the first frame of a message, which must start a
+    /// sequence if present.
+    fn first_chunk(&self) -> io::Result<Option<(usize, usize)>> {
+        match self.chunk {
+            Some((index, count)) if index != 1 => Err(invalid_data!(
+                "chunk {}/{} without its predecessors",
+                index,
+                count
+            )),
+            chunk => Ok(chunk),
+        }
+    }
+
+    /// Decode the body according to the headers.
+    fn finish(self, mut buf: Vec<u8>) -> io::Result<String> {
+        self.decode_body(&mut buf)?;
+        let buf = String::from_utf8(buf).map_err(invalid_data)?;
+        trace!("< {}", buf);
+        Ok(buf)
+    }
+
+    /// Decompress `buf` in place and check the charset, leaving the utf-8
+    /// validation to the caller.
+    fn decode_body(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        if let Some(encoding) = &self.encoding {
+            let encoding = ContentEncoding::from_name(encoding)
+                .ok_or_else(|| invalid_data!("unsupported Content-Encoding: {:?}", encoding))?;
+            *buf = encoding.decode(buf)?;
+        }
+        // The spec only defines utf-8, `utf8` is accepted for backwards compatibility.
+        let charset = self.content_type.as_deref().and_then(content_type_charset);
+        if let Some(charset) = charset {
+            if !charset.eq_ignore_ascii_case("utf-8") && !charset.eq_ignore_ascii_case("utf8") {
+                return Err(invalid_data!("unsupported charset: {:?}", charset));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Warn when a frame does not start like a header block. This usually means
+/// the previous frame had more bytes than its `Content-Length` declared, and
+/// the stray bytes now prefix this one.
+fn check_frame_start(line: &str) -> bool {
+    let looks_valid = line
+        .get(..8)
+        .is_some_and(|start| start.eq_ignore_ascii_case("Content-"))
+        || line.starts_with('{');
+    if !looks_valid {
+        warn!(
+            "Unexpected bytes where a header was expected, the previous frame may be longer than its Content-Length: {:?}",
+            line
+        );
+    }
+    looks_valid
+}
+
+/// Replace the contents of `buf` with the next `size` bytes. The buffer grows
+/// as the body arrives rather than up front, so a bogus `Content-Length`
+/// cannot allocate more than the input holds. A truncated body reports how
+/// much of it arrived.
+fn read_msg_body(
+    inp: &mut dyn BufRead,
+    buf: &mut Vec<u8>,
+    size: usize,
+    headers: &str,
+) -> io::Result<()> {
+    buf.clear();
+    let read = Read::take(inp, size as u64).read_to_end(buf)?;
+    if read < size {
+        return Err(truncated_body(size, read, headers));
+    }
+    Ok(())
+}
+
+/// Discard the body of the frame `header` was read from, without holding it
+/// in memory.
+fn skip_msg_body(inp: &mut dyn BufRead, header: &MsgHeader) -> io::Result<()> {
+    let size = header.content_length;
+    let read = io::copy(&mut Read::take(inp, size as u64), &mut io::sink())?;
+    if read < size as u64 {
+        return Err(truncated_body(size, read as usize, &header.headers));
+    }
+    Ok(())
+}
+
+fn truncated_body(size: usize, read: usize, headers: &str) -> io::Error {
+    TruncatedMessage::Body {
+        expected: size,
+        read,
+        headers: headers.to_owned(),
+    }
+    .into()
+}
+
+/// Like `read_msg_frames`.
+#[cfg(feature = "tokio")]
+async fn read_msg_frames_async(
+    inp: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    logger: Option<&FrameLogger>,
+    body: &mut Vec<u8>,
+    opts: &ReadOptions,
+) -> io::Result<Option<MsgHeader>> {
+    let header = match read_frame_into_async(inp, logger, body, opts).await? {
+        None => return Ok(None),
+        Some(header) => header,
+    };
+    if let Some((_, count)) = header.first_chunk()? {
+        for index in 2..=count {
+            let mut chunk = Vec::new();
+            let next = read_frame_into_async(inp, logger, &mut chunk, opts)
+                .await?
+                .map(|header| (header, chunk));
+            append_chunk(body, next, index, count, opts.max_content_length())?;
+        }
+    }
+    Ok(Some(header))
+}
+
+/// Like `read_frame_into`.
+#[cfg(feature = "tokio")]
+async fn read_frame_into_async(
+    inp: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    logger: Option<&FrameLogger>,
+    buf: &mut Vec<u8>,
+    opts: &ReadOptions,
+) -> io::Result<Option<MsgHeader>> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut parser = HeaderParser::new(opts);
+    let mut line = String::new();
+    let header = loop {
+        line.clear();
+        let read = (&mut *inp)
+            .take(opts.max_header_bytes() as u64 + 1)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 && parser.headers.is_empty() {
+            return Ok(None);
+        }
+        if let Some(header) = parser.push_read_line(&line, opts)? {
+            break header;
+        }
+    };
+    header.check_length(opts.max_content_length())?;
+
+    let size = header.content_length;
+    buf.clear();
+    let read = (&mut *inp).take(size as u64).read_to_end(buf).await?;
+    if read < size {
+        return Err(truncated_body(size, read, &header.headers));
+    }
+    if let Some(logger) = logger {
+        logger.log(Direction::Inbound, &header.headers, buf);
+    }
+    Ok(Some(header))
+}
+
+/// Extract the `charset` parameter of a `Content-Type` header value, e.g.
+/// `application/vscode-jsonrpc; charset=utf-8`.
+fn content_type_charset(value: &str) -> Option<&str> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+fn write_msg_text(
+    out: &mut dyn Write,
+    msg: &str,
+    encoding: Option<ContentEncoding>,
+) -> io::Result<()> {
+    write_msg_text_logged(out, msg, encoding, frame_log::global())
+}
+
+fn write_msg_text_logged(
+    out: &mut dyn Write,
+    msg: &str,
+    encoding: Option<ContentEncoding>,
+    logger: Option<&FrameLogger>,
+) -> io::Result<()> {
+    // debug!("> {}", msg);
+    let (headers, body) = match encoding {
+        None => (
+            // `str::len` counts bytes, as Content-Length requires.
+            format!("Content-Length: {}\r\n\r\n", msg.len()),
+            Cow::Borrowed(msg.as_bytes()),
+        ),
+        Some(encoding) => {
+            let body = encoding.encode(msg.as_bytes())?;
+            (
+                format!(
+                    "Content-Length: {}\r\nContent-Encoding: {}\r\n\r\n",
+                    body.len(),
+                    encoding.name()
+                ),
+                Cow::Owned(body),
+            )
+        }
+    };
+    write_frame(out, &headers, &body, logger)
+}
+
+fn write_frame(
+    out: &mut dyn Write,
+    headers: &str,
+    body: &[u8],
+    logger: Option<&FrameLogger>,
+) -> io::Result<()> {
+    out.write_all(headers.as_bytes())?;
+    out.write_all(body)?;
+    out.flush()?;
+    if let Some(logger) = logger {
+        logger.log(Direction::Outbound, headers, body);
+    }
+    Ok(())
+}
+
+/// Write `msg` as frames of at most `chunk_size` bytes, each with an
+/// `X-Chunk: i/n` header. A body that fits in one frame is written as usual.
+fn write_msg_chunks(
+    out: &mut dyn Write,
+    msg: &str,
+    encoding: Option<ContentEncoding>,
+    chunk_size: usize,
+) -> io::Result<()> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must be positive",
+        ));
+    }
+    let body = match encoding {
+        None => Cow::Borrowed(msg.as_bytes()),
+        Some(encoding) => Cow::Owned(encoding.encode(msg.as_bytes())?),
+    };
+    if body.len() <= chunk_size {
+        let encoding = encoding
+            .map(|encoding| format!("Content-Encoding: {}\r\n", encoding.name()))
+            .unwrap_or_default();
+        let headers = format!("Content-Length: {}\r\n{}\r\n", body.len(), encoding);
+        return write_frame(out, &headers, &body, frame_log::global());
+    }
+    let chunks: Vec<&[u8]> = body.chunks(chunk_size).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        // the encoding applies to the reassembled body
+        let encoding = match encoding {
+            Some(encoding) if i == 0 => format!("Content-Encoding: {}\r\n", encoding.name()),
+            _ => String::new(),
+        };
+        let headers = format!(
+            "Content-Length: {}\r\n{}X-Chunk: {}/{}\r\n\r\n",
+            chunk.len(),
+            encoding,
+            i + 1,
+            chunks.len()
+        );
+        write_frame(out, &headers, chunk, frame_log::global())?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+#[allow(dead_code)]
+pub enum ErrorCode {
+    // Defined by JSON RPC:
+    ParseError = -32700,
+    InvalidRequest = -32600,
+    MethodNotFound = -32601,
+    InvalidParams = -32602,
+    InternalError = -32603,
+    ServerErrorStart = -32099,
+    ServerErrorEnd = -32000,
+
+    /// Error code indicating that a server received a notification or
+    /// request before the server has received the `initialize` request.
+    ServerNotInitialized = -32002,
+    UnknownErrorCode = -32001,
+
+    // Defined by the protocol:
+    /// The client has canceled a request and a server has detected
+    /// the cancel.
+    RequestCanceled = -32800,
+
+    /// The server detected that the content of a document got
+    /// modified outside normal conditions. A server should
+    /// NOT send this error code if it detects a content change
+    /// in it unprocessed messages. The result even computed
+    /// on an older state might still be useful for the client.
+    ///
+    /// If a client decides that a result is not of any use anymore
+    /// the client should cancel the request.
+    ContentModified = -32801,
+
+    /// The server cancelled the request. This error code should
+    /// only be used for requests that explicitly support being
+    /// server cancellable.
+    ///
+    /// @since 3.17.0
+    ServerCancelled = -32802,
+
+    /// A request failed but it was syntactically correct, e.g the
+    /// method name was known and the parameters were valid. The error
+    /// message should contain human readable information about why
+    /// the request failed.
+    ///
+    /// @since 3.17.0
+    RequestFailed = -32803,
+}
+
+impl ErrorCode {
+    pub fn from_i32(code: i32) -> Option<ErrorCode> {
+        let code = match code {
+            -32700 => ErrorCode::ParseError,
+            -32600 => ErrorCode::InvalidRequest,
+            -32601 => ErrorCode::MethodNotFound,
+            -32602 => ErrorCode::InvalidParams,
+            -32603 => ErrorCode::InternalError,
+            -32099 => ErrorCode::ServerErrorStart,
+            -32000 => ErrorCode::ServerErrorEnd,
+            -32002 => ErrorCode::ServerNotInitialized,
+            -32001 => ErrorCode::UnknownErrorCode,
+            -32800 => ErrorCode::RequestCanceled,
+            -32801 => ErrorCode::ContentModified,
+            -32802 => ErrorCode::ServerCancelled,
+            -32803 => ErrorCode::RequestFailed,
+            _ => return None,
+        };
+        Some(code)
+    }
+}
+
+impl From<ErrorCode> for i32 {
+    fn from(code: ErrorCode) -> i32 {
+        code as i32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_id_conversion() {
+        let id = RequestId::from(42);
+        assert_eq!(id.as_i32(), Some(42));
+        assert_eq!(id.as_str(), None);
+        assert_eq!(i32::try_from(id).ok(), Some(42));
+
+        let id = RequestId::from("42".to_string());
+        assert_eq!(id.as_i32(), None);
+        assert_eq!(id.as_str(), Some("42"));
+        assert!(i32::try_from(id).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "completion cache: expected a numeric request id, got \"abc\"")]
+    fn test_request_id_expect_i32() {
+        assert_eq!(RequestId::from(7).expect_i32("completion cache"), 7);
+        RequestId::from("abc".to_string()).expect_i32("completion cache");
+    }
+
+    fn frame(body: &str) -> Vec<u8> {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+
+    /// Read back what `write` wrote, which is bytecode by default.
+    fn read_written(r: &mut impl BufRead) -> io::Result<Option<Message>> {
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..Default::default()
+        };
+        Message::read_with_options(r, &opts)
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let body = r#"[
+            {"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover", "params": {"uri": "file:///a.rs", "params": {"position": {"line": 1, "character": 2}}}},
+            {"jsonrpc": "2.0", "method": "textDocument/didSave", "params": {"uri": "file:///a.rs"}}
+        ]"#;
+        let batch = Message::read_batch(&mut frame(body).as_slice())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.len(), 2);
+        assert!(matches!(&batch[0], Message::Request(req) if req.method == "textDocument/hover"));
+        assert!(
+            matches!(&batch[1], Message::Notification(not) if not.method == "textDocument/didSave")
+        );
+        let json = |batch: &[Message]| -> Vec<serde_json::Value> {
+            batch
+                .iter()
+                .map(|msg| msg.clone().into_json().unwrap())
+                .collect()
+        };
+
+        let mut out = Vec::new();
+        Message::write_batch(batch.clone(), &mut out).unwrap();
+        let text = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        assert!(text.starts_with("[#["));
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..Default::default()
+        };
+        let read = Message::read_batch_with_options(&mut out.as_slice(), &opts)
+            .unwrap()
+            .unwrap();
+        assert_eq!(json(&read), json(&batch));
+
+        // only the element over the limit falls back to JSON
+        let hover = batch[0].clone().into_json().unwrap();
+        let limit = encode_json_value(&hover, &Default::default(), None)
+            .unwrap()
+            .len();
+        let options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                max_bytecode_bytes: Some(limit),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let large = Notification::new("test/large".to_string(), (0..100).collect::<Vec<_>>());
+        let batch = vec![batch[0].clone(), large.into()];
+        let mut out = Vec::new();
+        Message::write_batch_with_options(batch.clone(), &mut out, &options).unwrap();
+        let text = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        assert!(text.starts_with("[#["));
+        assert!(text.contains(r#",{"jsonrpc":"2.0","method":"test/large""#));
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..Default::default()
+        };
+        let read = Message::read_batch_with_options(&mut out.as_slice(), &opts)
+            .unwrap()
+            .unwrap();
+        assert_eq!(json(&read), json(&batch));
+
+        let single = frame(r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#);
+        let batch = Message::read_batch(&mut single.as_slice())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.len(), 1);
+    }
+
+    #[test]
+    fn test_batch_write_options() {
+        let batch: Vec<Message> = vec![
+            Request::new(RequestId::from(1), "shutdown".to_string(), ()).into(),
+            Notification::new("$/progress".to_string(), ()).into(),
+            Notification::new("exit".to_string(), ()).into(),
+        ];
+        let options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                min_bytecode_bytes: Some(usize::MAX),
+                ..Default::default()
+            },
+            notification_filter: Some(NotificationFilter::new().deny("$/*")),
+            emit_jsonrpc_field: false,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        Message::write_batch_with_options(batch, &mut out, &options).unwrap();
+        let text = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        let values: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap();
+        let methods: Vec<_> = values.iter().map(|value| &value["method"]).collect();
+        assert_eq!(methods, ["shutdown", "exit"]);
+        assert!(values.iter().all(|value| value.get("jsonrpc").is_none()));
+
+        // nothing is written once every element is filtered out
+        let options = WriteOptions {
+            notification_filter: Some(NotificationFilter::new().allow("textDocument/*")),
+            ..Default::default()
+        };
+        let batch = vec![Notification::new("exit".to_string(), ()).into()];
+        let mut out = Vec::new();
+        Message::write_batch_with_options(batch, &mut out, &options).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_bytecode_fallback() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let values: Vec<i32> = (0..1000).collect();
+        let not = Notification::new("test/large".to_string(), values);
+
+        let fallbacks = Arc::new(AtomicUsize::new(0));
+        let counter = fallbacks.clone();
+        let mut options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                on_fallback: Some(bytecode::FallbackHook(Arc::new(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }))),
+                max_bytecode_bytes: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        Message::from(not.clone())
+            .write_with_options(&mut out, &options)
+            .unwrap();
+        let text = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        assert!(text.starts_with('{'));
+        assert_eq!(fallbacks.load(Ordering::SeqCst), 1);
+
+        options.bytecode.strict = true;
+        let err = Message::from(not)
+            .write_with_options(&mut Vec::new(), &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fallbacks.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_signature_help_context() {
+        let params: Params = serde_json::from_value(serde_json::json!({
+            "uri": "file:///a.rs",
+            "context": {"signature-trigger-character": "("},
+        }))
+        .unwrap();
+        match params.context {
+            Some(Context::SignatureHelpContext(context)) => {
+                assert_eq!(context.signature_trigger_character, "(")
+            }
+            context => panic!("unexpected context {:?}", context),
+        }
+
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"language-server-id": 1})).unwrap();
+        assert!(matches!(context, Context::CommonContext(_)));
+    }
+
+    #[test]
+    fn test_eof_at_boundary_or_mid_frame() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let input = frame(body);
+        let mut inp = input.as_slice();
+        assert!(Message::read(&mut inp).unwrap().is_some());
+        // a clean disconnect between messages
+        assert!(Message::read(&mut inp).unwrap().is_none());
+
+        let truncated = |input: &[u8]| {
+            let err = Message::read(&mut &input[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{err}");
+            format!("{:?}", TruncatedMessage::of(&err).unwrap())
+        };
+        assert!(truncated(&input[..input.len() - 5]).starts_with("Body"));
+        assert!(truncated(b"Content-Length: 3\r\n").starts_with("Headers"));
+        assert!(truncated(b"Content-Len").starts_with("Headers"));
+
+        let mut chunked = Vec::new();
+        Message::from(Notification::new("test/chunked".to_string(), vec![1; 50]))
+            .write_chunked(&mut chunked, 32)
+            .unwrap();
+        let first = chunked.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4 + 32;
+        assert!(truncated(&chunked[..first]).starts_with("Chunk"));
+
+        // other errors are no truncation
+        let err = Message::read(&mut b"Content-Length: x\r\n\r\n".as_slice()).unwrap_err();
+        assert!(TruncatedMessage::of(&err).is_none());
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let input = "Content-Length: 10\r\n\r\n{}";
+        let err = read_msg_text(&mut input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let message = err.to_string();
+        assert!(message.contains("10 bytes"));
+        assert!(message.contains("only 2 bytes"));
+        assert!(message.contains("Content-Length: 10"));
+
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", MAX_CONTENT_LENGTH + 1);
+        let err = read_msg_text(&mut input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_cancel_notification() {
+        for id in [RequestId::from(7), RequestId::from("seven".to_string())] {
+            let not = Notification::cancel(id.clone());
+            assert_eq!(not.method, "$/cancelRequest");
+            assert_eq!(not.canceled_id(), Some(id));
+        }
+
+        let not = Notification::new("exit".to_string(), serde_json::json!({ "id": 7 }));
+        assert_eq!(not.canceled_id(), None);
+    }
+
+    #[test]
+    fn test_progress_notification() {
+        let values = [
+            serde_json::json!({"kind": "begin", "title": "Indexing", "percentage": 0}),
+            serde_json::json!({"kind": "report", "message": "1/2", "percentage": 50}),
+            serde_json::json!({"kind": "end"}),
+        ];
+        for value in values {
+            let not: Notification = serde_json::from_value(serde_json::json!({
+                "method": "$/progress",
+                "params": {"params": {"token": "rustAnalyzer/Indexing", "value": value}},
+            }))
+            .unwrap();
+            let progress = not.as_progress().unwrap();
+            assert_eq!(
+                progress.token,
+                ProgressToken::from("rustAnalyzer/Indexing".to_string())
+            );
+            assert_eq!(progress.value, value);
+        }
+
+        let not =
+            Notification::progress(ProgressToken::from(1), serde_json::json!({"kind": "end"}));
+        assert_eq!(not.as_progress().unwrap().token, ProgressToken::from(1));
+    }
+
+    #[test]
+    fn test_params_extra_fields() {
+        let params: Params = serde_json::from_value(serde_json::json!({
+            "uri": "file:///a.rs",
+            "params": {},
+            "workDoneToken": "token-1",
+        }))
+        .unwrap();
+        assert_eq!(params.extra["workDoneToken"], "token-1");
+
+        let value = serde_json::to_value(&params).unwrap();
+        assert_eq!(value["workDoneToken"], "token-1");
+
+        let params: Params = serde_json::from_value(serde_json::json!({"uri": null})).unwrap();
+        assert!(params.extra.is_empty());
+        let value = serde_json::to_value(&params).unwrap();
+        assert_eq!(value, serde_json::json!({"uri": null, "context": null}));
+    }
+
+    #[test]
+    fn test_diagnostics_context() {
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"language-server-id": 1, "version": 3}))
+                .unwrap();
+        match context {
+            Context::DiagnosticsContext(context) => {
+                assert_eq!(context.language_server_id, 1);
+                assert_eq!(context.version, Some(3));
+            }
+            context => panic!("unexpected context {:?}", context),
+        }
+
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"language-server-id": 1, "version": null}))
+                .unwrap();
+        assert!(matches!(
+            context,
+            Context::DiagnosticsContext(DiagnosticsContext { version: None, .. })
+        ));
+
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"language-server-id": 1})).unwrap();
+        assert!(matches!(context, Context::CommonContext(_)));
+    }
+
+    #[test]
+    fn test_new_with_context() {
+        let context = Context::CompletionContext(CompletionContext {
+            line: "let x = fo".to_string(),
+            prefix: "fo".to_string(),
+            start_point: 8,
+            bounds_start: 8,
+            trigger_kind: lsp_types::CompletionTriggerKind::INVOKED,
+        });
+        let req = Request::new_with(
+            RequestId::from(1),
+            "textDocument/completion".to_string(),
+            Some("file:///a.rs".to_string()),
+            Some(context),
+            serde_json::json!({}),
+        );
+        let value = serde_json::to_value(&req).unwrap();
+        assert_eq!(value["params"]["uri"], "file:///a.rs");
+        assert_eq!(value["params"]["context"]["prefix"], "fo");
+        assert_eq!(value["params"]["context"]["boundsStart"], 8);
+
+        let not = Notification::new_with(
+            "textDocument/didFocus".to_string(),
+            Some("file:///a.rs".to_string()),
+            Some(Context::CommonContext(CommonContext {
+                language_server_id: Some(2),
+            })),
+            (),
+        );
+        let value = serde_json::to_value(&not).unwrap();
+        assert_eq!(value["params"]["uri"], "file:///a.rs");
+        assert_eq!(value["params"]["context"]["language-server-id"], 2);
+    }
+
+    #[test]
+    fn test_content_encoding_round_trip() {
+        let items: Vec<String> = (0..2000).map(|i| format!("item-{}", i % 10)).collect();
+        let not = Notification::new("test/large".to_string(), items);
+
+        let mut plain = Vec::new();
+        Message::from(not.clone()).write(&mut plain).unwrap();
+        let expected = read_msg_text(&mut plain.as_slice()).unwrap().unwrap();
+
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let options = WriteOptions {
+                content_encoding: Some(encoding),
+                ..Default::default()
+            };
+            let mut compressed = Vec::new();
+            Message::from(not.clone())
+                .write_with_options(&mut compressed, &options)
+                .unwrap();
+            assert!(compressed.len() < plain.len() / 4);
+
+            let text = read_msg_text(&mut compressed.as_slice()).unwrap().unwrap();
+            assert_eq!(text, expected);
+        }
+
+        let input = "Content-Length: 2\r\nContent-Encoding: br\r\n\r\n{}";
+        let err = read_msg_text(&mut input.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("br"));
+    }
+
+    #[test]
+    fn test_message_accessors() {
+        let req: Message = Request::new(RequestId::from(1), "shutdown".to_string(), ()).into();
+        assert_eq!(req.method(), Some("shutdown"));
+        assert_eq!(req.id(), Some(&RequestId::from(1)));
+
+        let resp: Message = Response::new_ok(RequestId::from(1), ()).into();
+        assert_eq!(resp.method(), None);
+        assert_eq!(resp.id(), Some(&RequestId::from(1)));
+
+        let not: Message = Notification::new("exit".to_string(), ()).into();
+        assert_eq!(not.method(), Some("exit"));
+        assert_eq!(not.id(), None);
+    }
+
+    #[test]
+    fn test_extract_error_params() {
+        let params = serde_json::json!({"textDocument": 1});
+        let req = Request::new(
+            RequestId::from(1),
+            "textDocument/hover".to_string(),
+            params.clone(),
+        );
+        match req.extract::<lsp_types::HoverParams>("textDocument/hover") {
+            Err(ExtractError::JsonError { params: failed, .. }) => assert_eq!(failed, params),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let not = Notification::new("textDocument/didSave".to_string(), params.clone());
+        match not.extract::<lsp_types::DidSaveTextDocumentParams>("textDocument/didSave") {
+            Err(ExtractError::JsonError { params: failed, .. }) => assert_eq!(failed, params),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_workspace_context_roots() {
+        let legacy = serde_json::json!({"workspace-root": "/home/me/proj"});
+        let context: Context = serde_json::from_value(legacy.clone()).unwrap();
+        match context {
+            Context::WorkspaceContext(ref workspace) => {
+                assert_eq!(workspace.workspace_root, "/home/me/proj");
+                assert!(workspace.additional_roots.is_empty());
+            }
+            ref context => panic!("unexpected context {:?}", context),
+        }
+        assert_eq!(serde_json::to_value(&context).unwrap(), legacy);
+
+        let context: Context = serde_json::from_value(serde_json::json!({
+            "workspace-root": "/home/me/proj",
+            "additional-roots": ["/home/me/lib"],
+        }))
+        .unwrap();
+        match context {
+            Context::WorkspaceContext(workspace) => {
+                assert_eq!(workspace.additional_roots, vec!["/home/me/lib".to_string()])
+            }
+            context => panic!("unexpected context {:?}", context),
+        }
+    }
+
+    #[test]
+    fn test_jsonrpc_version() {
+        let input = frame(r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#);
+        assert!(Message::read(&mut input.as_slice()).unwrap().is_some());
+
+        let input = frame(r#"{"jsonrpc": "1.0", "method": "exit", "params": {}}"#);
+        let err = Message::read(&mut input.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("1.0"));
+
+        let input = frame(r#"{"method": "exit", "params": {}}"#);
+        assert!(Message::read(&mut input.as_slice()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_message_metrics() {
+        use std::cell::RefCell;
+
+        #[derive(Default)]
+        struct MockMetrics(RefCell<Vec<MessagePhase>>);
+
+        impl MessageMetrics for MockMetrics {
+            fn record(&self, phase: MessagePhase, _duration: Duration) {
+                self.0.borrow_mut().push(phase);
+            }
+        }
+
+        let metrics = MockMetrics::default();
+        let mut out = Vec::new();
+        Message::from(Notification::new("exit".to_string(), ()))
+            .write_with_metrics(&mut out, &WriteOptions::default(), &metrics)
+            .unwrap();
+        let input = frame(r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#);
+        Message::read_with_metrics(&mut input.as_slice(), &metrics)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            *metrics.0.borrow(),
+            vec![
+                MessagePhase::Bytecode,
+                MessagePhase::WriteText,
+                MessagePhase::ReadText,
+                MessagePhase::Deserialize,
+            ]
+        );
+    }
+
+    #[cfg(feature = "lsp-server-interop")]
+    #[test]
+    fn test_lsp_server_request() {
+        let text = r#"{"jsonrpc":"2.0","id":"a","method":"textDocument/hover","params":{"uri":"file:///a.rs","params":{"position":{"line":1,"character":2}}}}"#;
+        let Some(Message::Request(req)) = Message::read(&mut frame(text).as_slice()).unwrap()
+        else {
+            panic!("expected a request");
+        };
+        let converted = lsp_server::Request::from(req);
+        assert_eq!(converted.id, lsp_server::RequestId::from("a".to_string()));
+        assert_eq!(converted.method, "textDocument/hover");
+        assert_eq!(
+            converted.params,
+            serde_json::json!({"position": {"line": 1, "character": 2}})
+        );
+
+        let back = Request::from(lsp_server::Request::new(
+            lsp_server::RequestId::from(7),
+            "shutdown".to_string(),
+            serde_json::json!({"force": true}),
+        ));
+        assert_eq!(back.id, RequestId::from(7));
+        assert_eq!(back.method, "shutdown");
+        assert_eq!(back.params.params, serde_json::json!({"force": true}));
+        assert_eq!(back.params.uri, None);
+        assert_eq!(
+            RequestId::from(lsp_server::RequestId::from(RequestId::from(-3))),
+            RequestId::from(-3)
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_read_write() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            client
+                .write_all(&frame(
+                    r#"{"jsonrpc": "2.0", "id": 7, "method": "initialize", "params": {}}"#,
+                ))
+                .await
+                .unwrap();
+            Message::from(Notification::new("exit".to_string(), ()))
+                .write_async(&mut client, &WriteOptions::default())
+                .await
+                .unwrap();
+        });
+
+        let mut reader = BufReader::new(server);
+        let msg = Message::read_async(&mut reader).await.unwrap().unwrap();
+        assert_eq!(msg.method(), Some("initialize"));
+        assert_eq!(msg.id(), Some(&RequestId::from(7)));
+
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await.unwrap();
+        writer.await.unwrap();
+        let mut expected = Vec::new();
+        Message::from(Notification::new("exit".to_string(), ()))
+            .write(&mut expected)
+            .unwrap();
+        assert_eq!(raw, expected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_truncated_body() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = tokio::io::BufReader::new(server);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"Content-Length: 10\r\n\r\n{}")
+            .await
+            .unwrap();
+        drop(client);
+        let err = Message::read_async(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_read_options() {
+        let mut written = Vec::new();
+        Message::from(Notification::new("exit".to_string(), ()))
+            .write(&mut written)
+            .unwrap();
+        let read = |opts: ReadOptions| {
+            let written = written.clone();
+            async move { Message::read_with_options_async(&mut written.as_slice(), &opts).await }
+        };
+
+        // bytecode is only decoded when asked for, as by the sync reader
+        assert!(read(ReadOptions::default()).await.is_err());
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..ReadOptions::default()
+        };
+        let msg = read(opts).await.unwrap().unwrap();
+        assert_eq!(msg.method(), Some("exit"));
+
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            max_content_length: Some(4),
+            ..ReadOptions::default()
+        };
+        let err = read(opts).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_context_validate() {
+        let context = |start, end| ResolveContext {
+            language_server_id: 0,
+            start,
+            end,
+        };
+        assert_eq!(context(0, 0).validate(), Ok(()));
+        assert_eq!(context(3, 10).validate(), Ok(()));
+        assert_eq!(
+            context(10, 3).validate(),
+            Err(RangeError::Inverted { start: 10, end: 3 })
+        );
+        assert_eq!(
+            context(-1, 3).validate(),
+            Err(RangeError::Negative { start: -1, end: 3 })
+        );
+        assert_eq!(
+            context(3, -1).validate(),
+            Err(RangeError::Negative { start: 3, end: -1 })
+        );
+    }
+
+    #[test]
+    fn test_response_echoes_request_id() {
+        for id in [RequestId::from(3), RequestId::from("abc".to_string())] {
+            let req = Request::new(id.clone(), "shutdown".to_string(), ());
+            let ok = req.ok_response(());
+            assert_eq!(ok.id, id);
+            assert!(ok.error.is_none());
+
+            let err = req.err_response(ErrorCode::InternalError, "boom".to_string());
+            assert_eq!(err.id, id);
+            assert_eq!(err.error.unwrap().code, ErrorCode::InternalError as i32);
+        }
+    }
+
+    #[test]
+    fn test_multibyte_content_length() {
+        let text = "héllo 世界 🎉";
+        let mut out = Vec::new();
+        Message::from(Notification::new(
+            "window/logMessage".to_string(),
+            serde_json::json!({ "message": text }),
+        ))
+        .write(&mut out)
+        .unwrap();
+
+        let body = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        assert!(body.starts_with('#'));
+        assert!(body.contains(text));
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+        assert_eq!(out.len(), header.len() + body.len());
+        assert!(out.starts_with(header.as_bytes()));
+        assert!(body.len() > body.chars().count());
+    }
+
+    #[test]
+    fn test_error_code_conversion() {
+        for code in [ErrorCode::RequestCanceled, ErrorCode::ContentModified] {
+            assert_eq!(ErrorCode::from_i32(i32::from(code)), Some(code));
+        }
+        assert_eq!(i32::from(ErrorCode::RequestCanceled), -32800);
+        assert_eq!(
+            ErrorCode::from_i32(-32801),
+            Some(ErrorCode::ContentModified)
+        );
+        assert_eq!(ErrorCode::from_i32(-1), None);
+
+        let error = |code| ResponseError {
+            code,
+            message: String::new(),
+            data: None,
+        };
+        assert_eq!(error(-32800).error_code(), Some(ErrorCode::RequestCanceled));
+        assert_eq!(error(42).error_code(), None);
+    }
+
+    #[test]
+    fn test_duplicate_header() {
+        let input = b"Content-Length: 2\r\ncontent-length: 4\r\n\r\n{}{}";
+        let err = read_msg_text(&mut input.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("content-length"), "{err}");
+
+        let input = b"Content-Type: a\r\nContent-Type: b\r\nContent-Length: 2\r\n\r\n{}";
+        let err = read_msg_text(&mut input.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("Content-Type"), "{err}");
+    }
+
+    #[test]
+    fn test_completion_context_from_line() {
+        let check = |line: &str, column, prefix: &str, bounds_start, start_point| {
+            let context = CompletionContext::from_line(
+                line.to_string(),
+                column,
+                lsp_types::CompletionTriggerKind::INVOKED,
+            );
+            assert_eq!(context.line, line);
+            assert_eq!(context.prefix, prefix, "{line:?} at {column}");
+            assert_eq!(context.bounds_start, bounds_start, "{line:?} at {column}");
+            assert_eq!(context.start_point, start_point, "{line:?} at {column}");
+        };
+        check("let foo", 0, "", 0, 0);
+        check("let foo", 7, "foo", 4, 7);
+        check("let foo", 5, "f", 4, 5);
+        check("foo.bar_baz();", 11, "bar_baz", 4, 11);
+        check("self.", 5, "", 5, 5);
+        check("(setq lsp-copilot", 17, "lsp-copilot", 6, 17);
+        check("  héllo", 7, "héllo", 2, 7);
+        check("foo", 10, "foo", 0, 3);
+        check("", 0, "", 0, 0);
+    }
+
+    #[test]
+    fn test_completion_context_normalize_for_trigger() {
+        use lsp_types::CompletionTriggerKind;
+
+        let check = |trigger_kind, prefix: &str| {
+            let mut context = CompletionContext::from_line("foo.bar".to_string(), 7, trigger_kind);
+            context.normalize_for_trigger();
+            assert_eq!(context.prefix, prefix, "{trigger_kind:?}");
+            assert_eq!(context.start_point, 7);
+        };
+        check(CompletionTriggerKind::INVOKED, "bar");
+        check(CompletionTriggerKind::TRIGGER_CHARACTER, "");
+        check(
+            CompletionTriggerKind::TRIGGER_FOR_INCOMPLETE_COMPLETIONS,
+            "",
+        );
+    }
+
+    #[test]
+    fn test_frame_logger() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let logger = FrameLogger::new(buf.clone());
+
+        let inbound = frame(r#"{"jsonrpc": "2.0", "method": "exit"}"#);
+        read_msg_text_logged(&mut inbound.as_slice(), Some(&logger))
+            .unwrap()
+            .unwrap();
+        let mut outbound = Vec::new();
+        write_msg_text_logged(&mut outbound, "#[0 \"\" [] 1]", None, Some(&logger)).unwrap();
+
+        let log = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let inbound = String::from_utf8(inbound).unwrap();
+        let outbound = String::from_utf8(outbound).unwrap();
+        let (marker, rest) = log.split_once('\n').unwrap();
+        assert!(marker.ends_with(" <"), "{log:?}");
+        let rest = rest.strip_prefix(&format!("{inbound}\n")).unwrap();
+        let (marker, rest) = rest.split_once('\n').unwrap();
+        assert!(marker.ends_with(" >"), "{log:?}");
+        assert_eq!(rest, format!("{outbound}\n"));
+    }
+
+    #[test]
+    fn test_request_id_ordering() {
+        let mut ids = vec![
+            RequestId::from("b".to_string()),
+            RequestId::from(100),
+            RequestId::from("2".to_string()),
+            RequestId::from(-1),
+            RequestId::from("10".to_string()),
+            RequestId::from(2),
+        ];
+        ids.sort_by(RequestId::numeric_then_lexical_cmp);
+        assert_eq!(
+            ids,
+            vec![
+                RequestId::from(-1),
+                RequestId::from(2),
+                RequestId::from(100),
+                RequestId::from("10".to_string()),
+                RequestId::from("2".to_string()),
+                RequestId::from("b".to_string()),
+            ]
+        );
+
+        let mut derived = ids.clone();
+        derived.reverse();
+        derived.sort();
+        assert_eq!(derived, ids);
+    }
+
+    #[test]
+    fn test_into_lsp_params() {
+        use crate::lsp::jsonrpc;
+
+        let input = frame(
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover",
+                "params": {"uri": "file:///a.rs", "context": {"language-server-id": 1},
+                           "params": {"position": {"line": 1, "character": 2}},
+                           "bridge-only": true}}"#,
+        );
+        let req = match Message::read(&mut input.as_slice()).unwrap().unwrap() {
+            Message::Request(req) => req,
+            msg => panic!("unexpected message: {msg:?}"),
+        };
+        assert!(matches!(
+            req.params.proxy_context(),
+            Some(Context::CommonContext(_))
+        ));
+
+        let params = match req.params.into_lsp_params() {
+            serde_json::Value::Object(map) => jsonrpc::Params::Map(map),
+            params => panic!("unexpected params: {params:?}"),
+        };
+        let forwarded = serde_json::to_value(jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            id: req.id,
+            method: req.method,
+            params,
+        })
+        .unwrap();
+        assert_eq!(
+            forwarded["params"],
+            serde_json::json!({"position": {"line": 1, "character": 2}})
+        );
+        for field in ["uri", "context", "params", "bridge-only"] {
+            assert!(forwarded["params"].get(field).is_none(), "{field}");
+        }
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let msg = Message::from(Notification::new(
+            "test/large".to_string(),
+            (0..100).map(|i| format!("item {i}")).collect::<Vec<_>>(),
+        ));
+        let mut whole = Vec::new();
+        msg.clone().write(&mut whole).unwrap();
+        let expected = read_msg_text(&mut whole.as_slice()).unwrap().unwrap();
+
+        let chunk_size = expected.len() / 3 + 1;
+        let mut out = Vec::new();
+        msg.write_chunked(&mut out, chunk_size).unwrap();
+        let text = String::from_utf8_lossy(&out);
+        assert_eq!(text.matches("X-Chunk: ").count(), 3);
+        for i in 1..=3 {
+            assert!(text.contains(&format!("X-Chunk: {i}/3\r\n")));
+        }
+
+        let mut input = out.as_slice();
+        assert_eq!(read_msg_text(&mut input).unwrap().unwrap(), expected);
+        assert!(read_msg_text(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_chunked_write_options() {
+        let msg = Message::from(Notification::new(
+            "test/large".to_string(),
+            (0..100).map(|i| format!("item {i}")).collect::<Vec<_>>(),
+        ));
+        let options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                min_bytecode_bytes: Some(usize::MAX),
+                ..Default::default()
             },
+            content_encoding: Some(ContentEncoding::Gzip),
+            emit_jsonrpc_field: false,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        msg.clone()
+            .write_chunked_with_options(&mut out, 64, &options)
+            .unwrap();
+        let text = String::from_utf8_lossy(&out);
+        assert_eq!(text.matches("Content-Encoding: gzip\r\n").count(), 1);
+        assert!(text.contains("X-Chunk: 2/"));
+        let body = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["method"], "test/large");
+        assert!(value.get("jsonrpc").is_none());
+
+        let options = WriteOptions {
+            notification_filter: Some(NotificationFilter::new().deny("test/*")),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        msg.write_chunked_with_options(&mut out, 64, &options)
+            .unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_chunked_errors() {
+        let chunk = |i, n, body: &str| {
+            format!(
+                "Content-Length: {}\r\nX-Chunk: {i}/{n}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let read = |input: String| read_msg_text(&mut input.as_bytes()).unwrap_err();
+
+        assert_eq!(read(chunk(2, 2, "{}")).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(chunk(1, 2, "{")).kind(), io::ErrorKind::UnexpectedEof);
+        let skipped = chunk(1, 3, "[") + &chunk(3, 3, "]");
+        assert_eq!(read(skipped).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(chunk(0, 1, "{}")).kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(
+            Message::from(Notification::new("exit".to_string(), ()))
+                .write_chunked(&mut Vec::new(), 0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_empty_body() {
+        let input = b"Content-Length: 0\r\n\r\n";
+        let err = Message::read(&mut input.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("empty message body"), "{err}");
+    }
+
+    #[test]
+    fn test_lsp_method() {
+        let cases = [
+            ("initialize", LspMethod::Known(KnownMethod::Initialize)),
+            ("initialized", LspMethod::Known(KnownMethod::Initialized)),
+            ("shutdown", LspMethod::Known(KnownMethod::Shutdown)),
+            (
+                "$/cancelRequest",
+                LspMethod::Known(KnownMethod::CancelRequest),
+            ),
+            (
+                "textDocument/completion",
+                LspMethod::Known(KnownMethod::Completion),
+            ),
+            (
+                "emacs/getDocuments",
+                LspMethod::Other("emacs/getDocuments".to_string()),
+            ),
+        ];
+        for (method, expected) in cases {
+            let msg = Message::from(Notification::new(method.to_string(), ()));
+            assert_eq!(msg.lsp_method(), Some(expected.clone()));
+            assert_eq!(expected.as_str(), method);
+        }
+        let resp = Message::from(Response::new_ok(RequestId::from(1), ()));
+        assert_eq!(resp.lsp_method(), None);
+    }
+
+    #[test]
+    fn test_notification_is_initialized() {
+        assert!(Notification::new("initialized".to_string(), ()).is_initialized());
+        assert!(!Notification::new("initialize".to_string(), ()).is_initialized());
+    }
+
+    #[test]
+    fn test_params_file_path() {
+        let params = |uri: Option<&str>| Params {
+            uri: uri.map(str::to_string),
+            context: None,
+            contexts: None,
+            params: serde_json::Value::Null,
+            extra: Default::default(),
+        };
+
+        let unix = params(Some("file:///home/user/my%20project/main.rs"));
+        assert!(unix.uri_as_url().unwrap().is_ok());
+        #[cfg(not(windows))]
+        assert_eq!(
+            unix.file_path(),
+            Some(PathBuf::from("/home/user/my project/main.rs"))
+        );
+
+        #[cfg(not(windows))]
+        assert_eq!(
+            params(Some("file:///a:/x")).file_path(),
+            Some(PathBuf::from("/a:/x"))
+        );
+
+        let windows = params(Some("file:///C:/Users/me/main.rs"));
+        #[cfg(not(windows))]
+        assert_eq!(
+            windows.file_path(),
+            Some(PathBuf::from("/C:/Users/me/main.rs"))
+        );
+        #[cfg(windows)]
+        assert_eq!(
+            windows.file_path(),
+            Some(PathBuf::from(r"C:\Users\me\main.rs"))
+        );
+
+        let malformed = params(Some("not a uri"));
+        assert!(malformed.uri_as_url().unwrap().is_err());
+        assert_eq!(malformed.file_path(), None);
+
+        assert_eq!(params(Some("https://example.com/a.rs")).file_path(), None);
+        assert!(params(None).uri_as_url().is_none());
+    }
+
+    #[test]
+    fn test_id_allocator() {
+        let ids = IdAllocator::new(1000);
+        let allocated: Vec<RequestId> = (0..100).map(|_| ids.next_id()).collect();
+        let unique: std::collections::HashSet<_> = allocated.iter().collect();
+        assert_eq!(unique.len(), allocated.len());
+        assert_eq!(allocated[0], RequestId::from(1000));
+        assert_eq!(allocated[99], RequestId::from(1099));
+
+        let ids = IdAllocator::new(i32::MAX - 1);
+        assert_eq!(ids.next_id(), RequestId::from(i32::MAX - 1));
+        assert_eq!(ids.next_id(), RequestId::from(i32::MAX));
+        assert_eq!(ids.next_id(), RequestId::from(i32::MAX - 1));
+    }
+
+    /// Capture log records, for tests asserting on warnings. Records from
+    /// other tests running in parallel end up here too.
+    fn captured_logs() -> &'static std::sync::Mutex<Vec<String>> {
+        static LOGS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+        static INIT: std::sync::Once = std::sync::Once::new();
+
+        struct Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                LOGS.lock()
+                    .unwrap()
+                    .push(format!("{} {}", record.level(), record.args()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        INIT.call_once(|| {
+            log::set_logger(&Capture).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        &LOGS
+    }
+
+    #[test]
+    fn test_read_logs_summary() {
+        let logs = captured_logs();
+        let input = frame(
+            r#"{"jsonrpc": "2.0", "id": 4049, "method": "textDocument/hover", "params": {}}"#,
+        );
+        assert!(Message::read(&mut input.as_slice()).unwrap().is_some());
+        let logs = logs.lock().unwrap();
+        assert!(logs
+            .iter()
+            .any(|line| line == "INFO recv request method=textDocument/hover id=4049"));
+        // the full body is only logged at the trace level
+        assert!(logs
+            .iter()
+            .filter(|line| line.contains("4049"))
+            .all(|line| line.starts_with("INFO") || line.starts_with("TRACE")));
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let logs = captured_logs();
+        let mut input = frame(r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#);
+        input.extend_from_slice(b"garbage");
+        input.extend(frame(
+            r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#,
+        ));
+
+        let mut input = input.as_slice();
+        assert!(Message::read(&mut input).unwrap().is_some());
+        assert!(Message::read(&mut input).is_err());
+        assert!(logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|log| log.starts_with("WARN ") && log.contains("garbageContent-Length")));
+
+        assert!(check_frame_start("Content-Length: 2\r\n"));
+        assert!(check_frame_start("content-type: a\r\n"));
+        assert!(!check_frame_start("}\r\n"));
+    }
+
+    #[test]
+    fn test_message_from_value() {
+        let msg = Message::from_value(serde_json::json!({
+            "jsonrpc": "2.0", "id": 1, "method": "shutdown", "params": {}
+        }))
+        .unwrap();
+        assert!(matches!(msg, Message::Request(req) if req.is_shutdown()));
+        let msg = Message::from_value(serde_json::json!({"id": 1, "result": null})).unwrap();
+        assert!(matches!(msg, Message::Response(_)));
+        let msg = Message::from_value(serde_json::json!({"method": "exit", "params": {}})).unwrap();
+        assert!(matches!(msg, Message::Notification(not) if not.is_exit()));
+
+        let error = |value| match Message::from_value(value).unwrap_err() {
+            ExtractError::JsonError { error, .. } => error.to_string(),
+            ExtractError::MethodMismatch(_) => unreachable!(),
+        };
+        assert_eq!(
+            error(serde_json::json!({"jsonrpc": "2.0", "params": {}})),
+            "no `id` and no `method`"
+        );
+        assert_eq!(
+            error(serde_json::json!({"jsonrpc": "2.0", "id": 1})),
+            "no `method` and no `result` or `error`"
+        );
+        assert_eq!(error(serde_json::json!([1])), "message is not an object");
+        match Message::from_value(serde_json::json!({"id": 1, "method": "shutdown"})) {
+            Err(ExtractError::JsonError { method, .. }) => assert_eq!(method, "shutdown"),
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_read_with_buffer() {
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            let body = format!(
+                r#"{{"jsonrpc": "2.0", "id": {i}, "method": "textDocument/hover", "params": {{"params": {{"n": "{}"}}}}}}"#,
+                "x".repeat(i % 50)
+            );
+            input.extend(frame(&body));
+        }
+
+        let mut input = input.as_slice();
+        let mut buf = Vec::new();
+        for i in 0..1000 {
+            let req = match Message::read_with_buffer(&mut input, &mut buf).unwrap() {
+                Some(Message::Request(req)) => req,
+                msg => panic!("unexpected message: {msg:?}"),
+            };
+            assert_eq!(req.id, RequestId::from(i));
+            assert_eq!(req.params.params["n"], "x".repeat(i as usize % 50));
+        }
+        assert!(Message::read_with_buffer(&mut input, &mut buf)
+            .unwrap()
+            .is_none());
+        assert!(buf.capacity() >= 50);
+    }
+
+    #[test]
+    fn test_read_bytecode_frame() {
+        let req = Request::new(
+            RequestId::from(5),
+            "textDocument/hover".to_string(),
+            serde_json::json!({"position": {"line": 1, "character": 2}}),
+        );
+        let mut out = Vec::new();
+        Message::from(req.clone()).write(&mut out).unwrap();
+        assert!(read_msg_text(&mut out.as_slice())
+            .unwrap()
+            .unwrap()
+            .starts_with('#'));
+
+        match read_written(&mut out.as_slice()).unwrap().unwrap() {
+            Message::Request(read) => {
+                assert_eq!(read.id, req.id);
+                assert_eq!(read.method, req.method);
+                assert_eq!(read.params.params, req.params.params);
+            }
+            msg => panic!("unexpected message: {msg:?}"),
+        }
+
+        let err = Message::read(&mut frame("garbage").as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // only decoded when the reader accepts bytecode
+        let err = Message::read(&mut out.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // neither reader recurses without bound
+        let nested = frame(&format!("#{}", "[".repeat(1_000_000)));
+        let err = read_written(&mut nested.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Message::read(&mut nested.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_response_error_data_as() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct RetryData {
+            retry: bool,
+            #[serde(rename = "retryAfterMs")]
+            retry_after_ms: u32,
         }
+
+        let mut error = ResponseError {
+            code: ErrorCode::ContentModified as i32,
+            message: "content modified".to_string(),
+            data: Some(serde_json::json!({"retry": true, "retryAfterMs": 50})),
+        };
+        assert_eq!(
+            error.data_as::<RetryData>().unwrap().unwrap(),
+            RetryData {
+                retry: true,
+                retry_after_ms: 50
+            }
+        );
+        assert!(error.data_as::<Vec<i32>>().unwrap().is_err());
+
+        error.data = None;
+        assert!(error.data_as::<RetryData>().is_none());
     }
 
-    pub fn uri(&self) -> Option<String> {
-        self.params.uri.to_owned()
+    #[test]
+    fn test_workspace_canonical_root() {
+        let context = |root: &str| WorkspaceContext {
+            workspace_root: root.to_string(),
+            additional_roots: Vec::new(),
+        };
+        let cwd = std::env::current_dir().unwrap();
+
+        assert_eq!(
+            context("~/proj").canonical_root().unwrap(),
+            etcetera::home_dir().unwrap().join("proj")
+        );
+        assert_eq!(context("./rel").canonical_root().unwrap(), cwd.join("rel"));
+
+        let absolute = cwd.join("does-not-exist").join("..").join("proj");
+        assert_eq!(
+            context(absolute.to_str().unwrap())
+                .canonical_root()
+                .unwrap(),
+            cwd.join("proj")
+        );
     }
 
-    pub fn extract<P: DeserializeOwned>(
-        self,
-        method: &str,
-    ) -> Result<(RequestId, P), ExtractError<Request>> {
-        if self.method != method {
-            return Err(ExtractError::MethodMismatch(self));
+    #[test]
+    fn test_message_kind_predicates() {
+        let req: Message = Request::new(RequestId::from(1), "shutdown".to_string(), ()).into();
+        let resp: Message = Response::new_ok(RequestId::from(1), ()).into();
+        let not: Message = Notification::new("exit".to_string(), ()).into();
+
+        assert!(req.is_request() && !req.is_response() && !req.is_notification());
+        assert!(!resp.is_request() && resp.is_response() && !resp.is_notification());
+        assert!(!not.is_request() && !not.is_response() && not.is_notification());
+
+        assert_eq!(req.as_request().unwrap().method, "shutdown");
+        assert!(req.as_response().is_none() && req.as_notification().is_none());
+        assert_eq!(resp.as_response().unwrap().id, RequestId::from(1));
+        assert!(resp.as_request().is_none() && resp.as_notification().is_none());
+        assert_eq!(not.as_notification().unwrap().method, "exit");
+        assert!(not.as_request().is_none() && not.as_response().is_none());
+    }
+
+    #[test]
+    fn test_message_display() {
+        let req = Request::new_with(
+            RequestId::from(42),
+            "textDocument/completion".to_string(),
+            Some("file:///a.rs".to_string()),
+            None,
+            (),
+        );
+        assert_eq!(
+            Message::from(req).to_string(),
+            "Request#42 textDocument/completion uri=file:///a.rs"
+        );
+        let req = Request::new(RequestId::from("x".to_string()), "shutdown".to_string(), ());
+        assert_eq!(Message::from(req).to_string(), r#"Request#"x" shutdown"#);
+        assert_eq!(
+            Message::from(Notification::new("exit".to_string(), ())).to_string(),
+            "Notification exit"
+        );
+        assert_eq!(
+            Message::from(Response::new_ok(RequestId::from(42), ())).to_string(),
+            "Response#42 ok"
+        );
+        let err = Response::new_err(
+            RequestId::from(42),
+            ErrorCode::MethodNotFound,
+            "Method not found".to_string(),
+        );
+        assert_eq!(
+            Message::from(err).to_string(),
+            "Response#42 err(-32601: Method not found)"
+        );
+    }
+
+    #[test]
+    fn test_position_context() {
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"line": 3, "character": 7})).unwrap();
+        match context {
+            Context::PositionContext(position) => {
+                assert_eq!(
+                    lsp_types::Position::from(position),
+                    lsp_types::Position::new(3, 7)
+                );
+            }
+            context => panic!("unexpected context {:?}", context),
         }
-        match serde_json::from_value(self.params.params) {
-            Ok(params) => Ok((self.id, params)),
-            Err(error) => Err(ExtractError::JsonError {
-                method: self.method,
-                error,
+
+        let req: Request = serde_json::from_str(
+            r#"{"id": 1, "method": "textDocument/hover", "params": {"uri": "file:///a.rs",
+                "context": {"line": 0, "character": 2}, "params": {}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req.params.context,
+            Some(Context::PositionContext(PositionContext {
+                line: 0,
+                character: 2
+            }))
+        ));
+
+        // the other variants are unaffected
+        let context: Context = serde_json::from_value(serde_json::json!({
+            "line": "foo", "prefix": "foo", "startPoint": 3, "boundsStart": 0, "triggerKind": 1
+        }))
+        .unwrap();
+        assert!(matches!(context, Context::CompletionContext(_)));
+        let context: Context = serde_json::from_value(
+            serde_json::json!({"language-server-id": 1, "start": 0, "end": 1}),
+        )
+        .unwrap();
+        assert!(matches!(context, Context::ResolveContext(_)));
+    }
+
+    #[test]
+    fn test_rewrite_uri() {
+        let rewriter = UriRewriter::new().rule("file:///home/me/proj", "file:///workspace");
+
+        let mut req = Request::new_with(
+            RequestId::from(1),
+            "textDocument/definition".to_string(),
+            Some("file:///home/me/proj/src/lib.rs".to_string()),
+            None,
+            serde_json::json!({
+                "textDocument": {"uri": "file:///home/me/proj/src/lib.rs"},
+                "position": {"line": 0, "character": 0},
             }),
+        );
+        req.params.rewrite_uri(&rewriter);
+        assert_eq!(
+            req.params.uri.as_deref(),
+            Some("file:///workspace/src/lib.rs")
+        );
+        assert_eq!(
+            req.params.params["textDocument"]["uri"],
+            "file:///workspace/src/lib.rs"
+        );
+
+        let mut resp = Response::new_ok(
+            RequestId::from(1),
+            serde_json::json!([{
+                "uri": "file:///workspace/src/main.rs",
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 1}},
+            }]),
+        );
+        resp.rewrite_uri(&rewriter.reversed());
+        assert_eq!(
+            resp.result.unwrap()[0]["uri"],
+            "file:///home/me/proj/src/main.rs"
+        );
+
+        let mut not = Notification::new_with(
+            "textDocument/publishDiagnostics".to_string(),
+            Some("file:///workspace/src/main.rs".to_string()),
+            None,
+            serde_json::json!({"uri": "file:///workspace/src/main.rs", "diagnostics": []}),
+        );
+        not.params.rewrite_uri(&rewriter.reversed());
+        assert_eq!(
+            not.uri().as_deref(),
+            Some("file:///home/me/proj/src/main.rs")
+        );
+        assert_eq!(not.params.params["uri"], "file:///home/me/proj/src/main.rs");
+    }
+
+    #[test]
+    fn test_tagged_context() {
+        // a completion context missing `triggerKind` is taken for a common one...
+        let payload = serde_json::json!({
+            "language-server-id": 1, "line": "foo", "prefix": "foo", "startPoint": 3, "boundsStart": 0
+        });
+        let context: Context = serde_json::from_value(payload.clone()).unwrap();
+        assert!(matches!(context, Context::CommonContext(_)));
+
+        // ...unless its kind is given
+        let mut tagged = payload;
+        tagged[CONTEXT_KIND] = "completion".into();
+        let err = serde_json::from_value::<Context>(tagged.clone()).unwrap_err();
+        assert!(err.to_string().contains("triggerKind"), "{}", err);
+
+        tagged["triggerKind"] = 2.into();
+        let context: Context = serde_json::from_value(tagged).unwrap();
+        assert_eq!(context.kind(), "completion");
+        assert!(matches!(context, Context::CompletionContext(_)));
+
+        let context: Context = serde_json::from_value(
+            serde_json::json!({"context-kind": "common", "language-server-id": 1, "version": 2}),
+        )
+        .unwrap();
+        assert!(matches!(context, Context::CommonContext(_)));
+
+        let err = serde_json::from_value::<Context>(serde_json::json!({"context-kind": "nope"}))
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown context-kind `nope`"));
+        assert!(serde_json::from_value::<Context>(serde_json::json!("foo")).is_err());
+    }
+
+    #[test]
+    fn test_is_incremental_change() {
+        let did_change = |change: serde_json::Value| {
+            Notification::new(
+                "textDocument/didChange".to_string(),
+                serde_json::json!({
+                    "textDocument": {"uri": "file:///a.rs", "version": 2},
+                    "contentChanges": [change],
+                }),
+            )
+        };
+        let full = did_change(serde_json::json!({"text": "fn main() {}"}));
+        assert_eq!(full.is_incremental_change(), Some(false));
+
+        let incremental = did_change(serde_json::json!({
+            "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 2}},
+            "text": "pub fn",
+        }));
+        assert_eq!(incremental.is_incremental_change(), Some(true));
+
+        let save = Notification::new("textDocument/didSave".to_string(), ());
+        assert_eq!(save.is_incremental_change(), None);
+    }
+
+    #[test]
+    fn test_opened_closed_document() {
+        let open = Notification::new(
+            "textDocument/didOpen".to_string(),
+            serde_json::json!({
+                "textDocument": {
+                    "uri": "file:///a.rs",
+                    "languageId": "rust",
+                    "version": 0,
+                    "text": "",
+                },
+            }),
+        );
+        assert_eq!(
+            open.opened_document(),
+            Some(("file:///a.rs".to_string(), "rust".to_string()))
+        );
+        assert_eq!(open.closed_document(), None);
+
+        let close = Notification::new(
+            "textDocument/didClose".to_string(),
+            serde_json::json!({"textDocument": {"uri": "file:///a.rs"}}),
+        );
+        assert_eq!(close.closed_document().as_deref(), Some("file:///a.rs"));
+        assert_eq!(close.opened_document(), None);
+
+        let malformed = Notification::new(
+            "textDocument/didOpen".to_string(),
+            serde_json::json!({"textDocument": {"uri": "file:///a.rs"}}),
+        );
+        assert_eq!(malformed.opened_document(), None);
+    }
+
+    #[test]
+    fn test_document_version() {
+        let change = Notification::new(
+            "textDocument/didChange".to_string(),
+            serde_json::json!({
+                "textDocument": {"uri": "file:///a.rs", "version": 7},
+                "contentChanges": [{"text": ""}],
+            }),
+        );
+        assert_eq!(change.document_version(), Some(7));
+
+        let save = Notification::new(
+            "textDocument/didSave".to_string(),
+            serde_json::json!({"textDocument": {"uri": "file:///a.rs", "version": 7}}),
+        );
+        assert_eq!(save.document_version(), None);
+        let unversioned = Notification::new(
+            "textDocument/didChange".to_string(),
+            serde_json::json!({"textDocument": {"uri": "file:///a.rs"}, "contentChanges": []}),
+        );
+        assert_eq!(unversioned.document_version(), None);
+    }
+
+    #[test]
+    fn test_lenient_framing() {
+        let logs = captured_logs();
+        let body = r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#;
+        let crlf = frame(body);
+        let lf = format!("Content-Length: {}\n\n{}", body.len(), body).into_bytes();
+        let lenient = ReadOptions {
+            framing: Framing::Lenient,
+            ..ReadOptions::default()
+        };
+
+        let err = Message::read(&mut lf.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("malformed header"));
+
+        for input in [&crlf, &lf] {
+            let msg = Message::read_with_options(&mut input.as_slice(), &lenient)
+                .unwrap()
+                .unwrap();
+            assert!(matches!(msg, Message::Notification(not) if not.is_exit()));
         }
+        assert!(logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.starts_with("WARN accepting header lines ending with a bare")));
     }
 
-    pub(crate) fn is_shutdown(&self) -> bool {
-        self.method == "shutdown"
+    #[test]
+    fn test_new_err_with_data() {
+        let resp = Response::new_err_with_data(
+            RequestId::from(3),
+            ErrorCode::ContentModified,
+            "document changed".to_string(),
+            serde_json::json!({"version": 7}),
+        );
+        assert_eq!(
+            serde_json::to_value(&resp).unwrap()["error"],
+            serde_json::json!({
+                "code": -32801,
+                "message": "document changed",
+                "data": {"version": 7},
+            })
+        );
     }
-    pub(crate) fn is_initialize(&self) -> bool {
-        self.method == "initialize"
+
+    #[test]
+    fn test_read_with_limit() {
+        let body = r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#;
+        let input = frame(body);
+
+        let msg = Message::read_with_limit(&mut input.as_slice(), body.len()).unwrap();
+        assert!(msg.is_some());
+
+        let err = Message::read_with_limit(&mut input.as_slice(), body.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .contains(&format!("exceeds the limit of {} bytes", body.len() - 1)));
+
+        // chunks count towards the limit of the whole message
+        let half = body.len() / 2;
+        let mut chunked = Vec::new();
+        for (index, part) in [&body[..half], &body[half..]].iter().enumerate() {
+            chunked.extend(
+                format!(
+                    "Content-Length: {}\r\nX-Chunk: {}/2\r\n\r\n{}",
+                    part.len(),
+                    index + 1,
+                    part
+                )
+                .into_bytes(),
+            );
+        }
+        assert!(
+            Message::read_with_limit(&mut chunked.as_slice(), body.len())
+                .unwrap()
+                .is_some()
+        );
+        assert!(Message::read_with_limit(&mut chunked.as_slice(), body.len() - 1).is_err());
     }
-}
 
-#[allow(dead_code)]
-impl Notification {
-    pub fn new(method: String, params: impl Serialize) -> Notification {
-        Notification {
-            method,
-            params: Params {
-                uri: None,
+    #[test]
+    fn test_common_context_id() {
+        let context: Context =
+            serde_json::from_value(serde_json::json!({"language-server-id": 3})).unwrap();
+        let Context::CommonContext(context) = context else {
+            panic!("expected a common context");
+        };
+        assert_eq!(context.resolve(0), 3);
+        assert_eq!(context.require(), Ok(3));
+
+        let context: Context = serde_json::from_value(serde_json::json!({})).unwrap();
+        let Context::CommonContext(context) = context else {
+            panic!("expected a common context");
+        };
+        assert_eq!(context.resolve(0), 0);
+        assert_eq!(
+            context.require(),
+            Err(ContextError::MissingLanguageServerId)
+        );
+        assert_eq!(
+            serde_json::to_value(&context).unwrap(),
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn test_sequenced_messages() {
+        use std::{cmp::Reverse, collections::BinaryHeap};
+
+        let mut input = Vec::new();
+        for id in [30, 20, 10] {
+            let req = Request::new(RequestId::from(id), "textDocument/hover".to_string(), ());
+            input.extend(frame(&Message::from(req).into_json().unwrap().to_string()));
+        }
+        let req = Request::new(RequestId::from("a".to_string()), "shutdown".to_string(), ());
+        input.extend(frame(&Message::from(req).into_json().unwrap().to_string()));
+
+        let sequencer = MessageSequencer::new();
+        let mut input = input.as_slice();
+        let mut queue = BinaryHeap::new();
+        while let Some(msg) = sequencer.read(&mut input).unwrap() {
+            queue.push(Reverse(msg));
+        }
+        let ids: Vec<String> = std::iter::from_fn(|| queue.pop())
+            .map(|Reverse(msg)| msg.msg.id().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, ["30", "20", "10", r#""a""#]);
+    }
+
+    #[test]
+    fn test_from_lsp() {
+        use lsp_types::{
+            request::Completion, CompletionParams, TextDocumentIdentifier,
+            TextDocumentPositionParams,
+        };
+
+        let uri = lsp_types::Url::parse("file:///a.rs").unwrap();
+        let req = Request::from_lsp::<Completion>(
+            RequestId::from(1),
+            CompletionParams {
+                text_document_position: TextDocumentPositionParams::new(
+                    TextDocumentIdentifier::new(uri.clone()),
+                    lsp_types::Position::new(1, 2),
+                ),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
                 context: None,
-                params: serde_json::to_value(params).unwrap(),
             },
+        );
+        assert_eq!(req.method, "textDocument/completion");
+        assert_eq!(req.params.params["position"]["character"], 2);
+
+        let not = Notification::from_lsp::<lsp_types::notification::DidChangeTextDocument>(
+            lsp_types::DidChangeTextDocumentParams {
+                text_document: lsp_types::VersionedTextDocumentIdentifier::new(uri, 3),
+                content_changes: vec![lsp_types::TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "fn main() {}".to_string(),
+                }],
+            },
+        );
+        assert_eq!(not.method, "textDocument/didChange");
+        assert_eq!(not.params.params["textDocument"]["version"], 3);
+        assert_eq!(not.is_incremental_change(), Some(false));
+    }
+
+    #[test]
+    fn test_extract_lsp() {
+        use lsp_types::{request::Completion, CompletionResponse};
+
+        let resp = Response::new_ok(
+            RequestId::from(1),
+            serde_json::json!([{"label": "foo"}, {"label": "bar"}]),
+        );
+        match resp.extract_lsp::<Completion>().unwrap() {
+            Some(CompletionResponse::Array(items)) => {
+                let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+                assert_eq!(labels, ["foo", "bar"]);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let resp = Response::new_err(
+            RequestId::from(1),
+            ErrorCode::RequestFailed,
+            "no completions".to_string(),
+        );
+        match resp.extract_lsp::<Completion>() {
+            Err(ResponseExtractError::Error(error)) => assert_eq!(error.message, "no completions"),
+            result => panic!("unexpected result {:?}", result),
         }
+
+        // a null result
+        let resp: Response = serde_json::from_str(r#"{"id": 1, "result": null}"#).unwrap();
+        assert!(resp.extract_lsp::<Completion>().unwrap().is_none());
+        let resp: Response = serde_json::from_str(r#"{"id": 1}"#).unwrap();
+        assert!(matches!(
+            resp.extract_lsp::<lsp_types::request::ResolveCompletionItem>(),
+            Err(ResponseExtractError::JsonError { .. })
+        ));
     }
 
-    pub fn uri(&self) -> Option<String> {
-        self.params.uri.to_owned()
+    #[test]
+    fn test_telemetry() {
+        let not = Notification::telemetry("coalesced", serde_json::json!({"count": 3}));
+        assert_eq!(not.method, "telemetry/event");
+        assert_eq!(
+            not.as_telemetry(),
+            Some(TelemetryEvent {
+                event: "coalesced".to_string(),
+                data: serde_json::json!({"count": 3}),
+            })
+        );
+        let event: TelemetryEvent = not.extract("telemetry/event").unwrap();
+        assert_eq!(event.data["count"], 3);
+        assert_eq!(
+            Notification::new("exit".to_string(), ()).as_telemetry(),
+            None
+        );
     }
 
-    pub fn extract<P: DeserializeOwned>(
-        self,
-        method: &str,
-    ) -> Result<P, ExtractError<Notification>> {
-        if self.method != method {
-            return Err(ExtractError::MethodMismatch(self));
+    #[test]
+    fn test_context_set() {
+        let mut set = ContextSet::new();
+        set.insert(Context::CommonContext(CommonContext {
+            language_server_id: Some(1),
+        }));
+        set.insert(Context::WorkspaceContext(WorkspaceContext {
+            workspace_root: "/proj".to_string(),
+            additional_roots: Vec::new(),
+        }));
+        let value = serde_json::to_value(&set).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "common": {"language-server-id": 1},
+                "workspace": {"workspace-root": "/proj"},
+            })
+        );
+
+        let set: ContextSet = serde_json::from_value(value).unwrap();
+        assert_eq!(set.common().unwrap().language_server_id, Some(1));
+        assert_eq!(set.workspace().unwrap().workspace_root, "/proj");
+        assert!(set.completion().is_none());
+
+        let req: Request = serde_json::from_str(
+            r#"{"id": 1, "method": "workspace/symbol", "params": {
+                "context": {"language-server-id": 2},
+                "contexts": {"workspace": {"workspace-root": "/proj"}}, "params": {}}}"#,
+        )
+        .unwrap();
+        let set = req.params.context_set();
+        assert_eq!(set.common().unwrap().language_server_id, Some(2));
+        assert_eq!(set.workspace().unwrap().workspace_root, "/proj");
+        let value = serde_json::to_value(&req).unwrap();
+        assert_eq!(
+            value["params"]["contexts"]["workspace"]["workspace-root"],
+            "/proj"
+        );
+    }
+
+    #[test]
+    fn test_read_recoverable() {
+        let mut input = frame(r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#);
+        input.extend(frame(r#"{"jsonrpc": "2.0", "method": "#));
+        // rejected for their headers, but still delimited
+        input.extend(b"Content-Length: 0\r\n\r\n");
+        let body = r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#;
+        input.extend(
+            format!(
+                "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=latin1\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        input.extend(frame(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "shutdown", "params": {}}"#,
+        ));
+
+        let mut input = input.as_slice();
+        let mut messages = Vec::new();
+        let mut skipped = Vec::new();
+        loop {
+            match Message::read_recoverable(&mut input).unwrap() {
+                ReadOutcome::Message(msg) => messages.push(msg),
+                ReadOutcome::Skipped { reason } => skipped.push(reason),
+                ReadOutcome::Eof => break,
+            }
         }
-        match serde_json::from_value(self.params.params) {
-            Ok(params) => Ok(params),
-            Err(error) => Err(ExtractError::JsonError {
-                method: self.method,
-                error,
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[1], Message::Request(req) if req.is_shutdown()));
+        assert_eq!(skipped.len(), 3);
+        assert!(skipped[0].contains("EOF while parsing"), "{}", skipped[0]);
+        assert!(skipped[1].contains("empty message body"), "{}", skipped[1]);
+        assert!(skipped[2].contains("unsupported charset"), "{}", skipped[2]);
+
+        // a frame that cannot be delimited is still an error
+        let mut input = &b"Content-Type: text/plain\r\n\r\n{}"[..];
+        assert!(Message::read_recoverable(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_set_trace() {
+        for (value, level) in [
+            ("off", TraceLevel::Off),
+            ("messages", TraceLevel::Messages),
+            ("verbose", TraceLevel::Verbose),
+            ("chatty", TraceLevel::Off),
+        ] {
+            let not: Notification = serde_json::from_value(serde_json::json!({
+                "method": "$/setTrace",
+                "params": {"params": {"value": value}},
+            }))
+            .unwrap();
+            assert_eq!(not.as_set_trace(), Some(level), "{}", value);
+        }
+
+        let not = Notification::set_trace(TraceLevel::Verbose);
+        assert_eq!(not.params.params, serde_json::json!({"value": "verbose"}));
+        assert_eq!(not.as_set_trace(), Some(TraceLevel::Verbose));
+        assert_eq!(
+            Notification::new("exit".to_string(), ()).as_set_trace(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_server_initiated() {
+        let configuration = Request::new(
+            RequestId::from(1),
+            "workspace/configuration".to_string(),
+            serde_json::json!({"items": []}),
+        );
+        assert!(configuration.is_server_initiated());
+        let hover = Request::new(RequestId::from(2), "textDocument/hover".to_string(), ());
+        assert!(!hover.is_server_initiated());
+
+        let methods = ["custom/ask"];
+        let custom = Request::new(RequestId::from(3), "custom/ask".to_string(), ());
+        assert!(custom.is_server_initiated_with(&methods));
+        assert!(!configuration.is_server_initiated_with(&methods));
+    }
+
+    #[test]
+    fn test_text_document_position() {
+        let request =
+            |method: &str, params| Request::new(RequestId::from(1), method.to_string(), params);
+        let hover = request(
+            "textDocument/hover",
+            serde_json::json!({
+                "textDocument": {"uri": "file:///a.rs"},
+                "position": {"line": 3, "character": 7},
             }),
+        );
+        assert_eq!(
+            hover.text_document_position(),
+            Some(("file:///a.rs".to_string(), lsp_types::Position::new(3, 7)))
+        );
+        let definition = request(
+            "textDocument/definition",
+            serde_json::json!({
+                "textDocument": {"uri": "file:///b.rs"},
+                "position": {"line": 0, "character": 0},
+                "workDoneToken": "t",
+            }),
+        );
+        assert_eq!(
+            definition.text_document_position(),
+            Some(("file:///b.rs".to_string(), lsp_types::Position::new(0, 0)))
+        );
+
+        let symbols = request(
+            "textDocument/documentSymbol",
+            serde_json::json!({"textDocument": {"uri": "file:///a.rs"}}),
+        );
+        assert_eq!(symbols.text_document_position(), None);
+    }
+
+    #[test]
+    fn test_request_id_with_server() {
+        for (server, inner) in [(0, 0), (1, 42), (7, 1 << 20), (255, RequestId::MAX_INNER)] {
+            let id = RequestId::with_server(server, inner).unwrap();
+            assert!(id.as_i32().unwrap() < 0);
+            assert_eq!(id.server_and_inner(), Some((server, inner)), "{id}");
         }
+        // distinct servers never share an id
+        assert_ne!(
+            RequestId::with_server(1, 5).unwrap(),
+            RequestId::with_server(2, 5).unwrap()
+        );
+
+        assert!(matches!(
+            RequestId::with_server(0, RequestId::MAX_INNER + 1),
+            Err(RequestIdError::InnerOutOfRange(_))
+        ));
+        assert!(matches!(
+            RequestId::with_server(0, -1),
+            Err(RequestIdError::InnerOutOfRange(-1))
+        ));
+
+        assert_eq!(RequestId::from(42).server_and_inner(), None);
+        assert_eq!(RequestId::from("42".to_string()).server_and_inner(), None);
     }
-    pub(crate) fn is_exit(&self) -> bool {
-        self.method == "exit"
+
+    #[test]
+    fn test_execute_command() {
+        let request = |params| {
+            Request::new(
+                RequestId::from(1),
+                "workspace/executeCommand".to_string(),
+                params,
+            )
+        };
+        let apply = request(serde_json::json!({
+            "command": "rust-analyzer.applySourceChange",
+            "arguments": [{"label": "fix"}, 3],
+        }));
+        assert!(apply.is_execute_command());
+        let (command, arguments) = apply.execute_command().unwrap();
+        assert_eq!(command, "rust-analyzer.applySourceChange");
+        assert_eq!(
+            arguments,
+            [serde_json::json!({"label": "fix"}), serde_json::json!(3)]
+        );
+
+        let reload = request(serde_json::json!({"command": "rust-analyzer.reloadWorkspace"}));
+        assert_eq!(
+            reload.execute_command(),
+            Some(("rust-analyzer.reloadWorkspace".to_string(), Vec::new()))
+        );
+
+        let hover = Request::new(RequestId::from(2), "textDocument/hover".to_string(), ());
+        assert!(!hover.is_execute_command());
+        assert_eq!(hover.execute_command(), None);
     }
-    pub(crate) fn is_initialize(&self) -> bool {
-        self.method == "initialize"
+
+    #[test]
+    fn test_configuration() {
+        let req = Request::new(
+            RequestId::from(4),
+            "workspace/configuration".to_string(),
+            serde_json::json!({"items": [
+                {"scopeUri": "file:///proj", "section": "rust-analyzer"},
+                {"section": "files.exclude"},
+            ]}),
+        );
+        let items = req.configuration_items().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].section.as_deref(), Some("rust-analyzer"));
+        assert_eq!(
+            items[0].scope_uri.as_ref().unwrap().as_str(),
+            "file:///proj"
+        );
+        assert_eq!(items[1].section.as_deref(), Some("files.exclude"));
+        assert!(items[1].scope_uri.is_none());
+
+        let hover = Request::new(RequestId::from(5), "textDocument/hover".to_string(), ());
+        assert!(hover.configuration_items().is_none());
+
+        let resp = Response::configuration(
+            req.id.clone(),
+            vec![
+                serde_json::json!({"checkOnSave": true}),
+                serde_json::Value::Null,
+            ],
+        );
+        assert_eq!(resp.id, RequestId::from(4));
+        let values = resp
+            .extract_lsp::<lsp_types::request::WorkspaceConfiguration>()
+            .unwrap();
+        assert_eq!(values.len(), items.len());
+        assert_eq!(values[0]["checkOnSave"], true);
     }
-}
 
-fn read_msg_text(inp: &mut dyn BufRead) -> io::Result<Option<String>> {
-    fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
-        io::Error::new(io::ErrorKind::InvalidData, error)
+    /// A xorshift generator, for reproducible pseudo-random inputs.
+    fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
     }
-    macro_rules! invalid_data {
-        ($($tt:tt)*) => (invalid_data(format!($($tt)*)))
+
+    // A fixed run of what the `read` target in `fuzz/` does, from the same
+    // seed corpus.
+    #[test]
+    fn test_read_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/framing");
+        let seeds: Vec<Vec<u8>> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        assert!(!seeds.is_empty());
+
+        // every truncation of every seed, and random byte mutations
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+        let mut inputs = Vec::new();
+        for seed in &seeds {
+            inputs.extend((0..=seed.len()).map(|len| seed[..len].to_vec()));
+            for _ in 0..200 {
+                let mut input = seed.clone();
+                for _ in 0..=next() % 4 {
+                    let i = next() as usize % input.len();
+                    input[i] = next() as u8;
+                }
+                inputs.push(input);
+            }
+        }
+        // only `Ok(Some)`, `Ok(None)` or `Err`, never a panic
+        for input in &inputs {
+            let mut text = input.as_slice();
+            while let Ok(Some(_)) = read_msg_text(&mut text) {}
+            let mut msg = input.as_slice();
+            while let Ok(Some(_)) = Message::read(&mut msg) {}
+            let mut msg = input.as_slice();
+            while let Ok(Some(_)) = read_written(&mut msg) {}
+        }
     }
 
-    let mut size = None;
-    let mut buf = String::new();
+    #[test]
+    fn test_write_read_round_trip() {
+        fn value(next: &mut impl FnMut() -> u64, depth: u32) -> serde_json::Value {
+            const CHARS: &[char] = &[
+                'a', 'Z', '0', ' ', '"', '\\', '\n', '\u{1}', 'é', '世', '🦀',
+            ];
+            let string = |next: &mut dyn FnMut() -> u64| -> String {
+                (0..next() % 12)
+                    .map(|_| CHARS[next() as usize % CHARS.len()])
+                    .collect()
+            };
+            match next() % if depth == 0 { 3 } else { 5 } {
+                0 => serde_json::json!(next() as i64 >> (next() % 64)),
+                1 => serde_json::json!(string(next)),
+                2 => serde_json::json!(true),
+                3 => (0..next() % 4).map(|_| value(next, depth - 1)).collect(),
+                _ => (0..1 + next() % 3)
+                    .map(|_| (format!("k{}", string(next)), value(next, depth - 1)))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into(),
+            }
+        }
+
+        let mut next = xorshift(0x9e37_79b9_7f4a_7c15);
+        for i in 0..300 {
+            let params = serde_json::json!({ "value": value(&mut next, 3) });
+            let msg: Message = match i % 3 {
+                0 => Notification::new("test/fuzz".to_string(), params).into(),
+                1 => Request::new(RequestId::from(i), "test/fuzz".to_string(), params).into(),
+                _ => Response::new_ok(RequestId::from(i), params).into(),
+            };
+            let json = msg.clone().into_json().unwrap();
+            let text = serde_json::to_string(&json).unwrap();
 
-    loop {
-        buf.clear();
-        if inp.read_line(&mut buf)? == 0 {
-            return Ok(None);
+            // the JSON body comes back byte for byte, compressed or not
+            for encoding in [None, Some(ContentEncoding::Gzip)] {
+                let mut buf = Vec::new();
+                write_msg_text(&mut buf, &text, encoding).unwrap();
+                assert_eq!(read_msg_text(&mut buf.as_slice()).unwrap().unwrap(), text);
+            }
+
+            // and the message survives the bytecode encoding
+            let mut buf = Vec::new();
+            msg.write(&mut buf).unwrap();
+            let read = read_written(&mut buf.as_slice()).unwrap().unwrap();
+            assert_eq!(read.into_json().unwrap(), json, "{}", text);
         }
-        if !buf.ends_with("\r\n") {
-            return Err(invalid_data!("malformed header: {:?}", buf));
+    }
+
+    #[test]
+    fn test_notification_filter() {
+        fn written(filter: NotificationFilter) -> Vec<Message> {
+            let options = WriteOptions {
+                notification_filter: Some(filter),
+                ..Default::default()
+            };
+            let messages: Vec<Message> = vec![
+                Notification::new("$/progress".to_string(), serde_json::json!({"token": 1})).into(),
+                Notification::new(
+                    "textDocument/publishDiagnostics".to_string(),
+                    serde_json::json!({"uri": "file:///a.rs", "diagnostics": []}),
+                )
+                .into(),
+                Notification::new(
+                    "window/logMessage".to_string(),
+                    serde_json::json!({"message": "hi"}),
+                )
+                .into(),
+                Request::new(RequestId::from(1), "$/progress".to_string(), ()).into(),
+                Response::new_ok(RequestId::from(2), ()).into(),
+            ];
+            let mut buf = Vec::new();
+            for msg in messages {
+                msg.write_with_options(&mut buf, &options).unwrap();
+            }
+            let mut buf = buf.as_slice();
+            std::iter::from_fn(|| read_written(&mut buf).unwrap()).collect()
         }
-        let buf = &buf[..buf.len() - 2];
-        if buf.is_empty() {
-            break;
+        fn notifications(messages: &[Message]) -> Vec<&str> {
+            messages
+                .iter()
+                .filter_map(|msg| match msg {
+                    Message::Notification(not) => Some(not.method.as_str()),
+                    _ => None,
+                })
+                .collect()
         }
-        let mut parts = buf.splitn(2, ": ");
-        let header_name = parts.next().unwrap();
-        let header_value = parts
-            .next()
-            .ok_or_else(|| invalid_data!("malformed header: {:?}", buf))?;
-        if header_name.eq_ignore_ascii_case("Content-Length") {
-            size = Some(header_value.parse::<usize>().map_err(invalid_data)?);
+
+        let messages = written(NotificationFilter::new().deny("$/progress"));
+        assert_eq!(messages.len(), 4);
+        assert_eq!(
+            notifications(&messages),
+            ["textDocument/publishDiagnostics", "window/logMessage"]
+        );
+
+        let messages = written(NotificationFilter::new().allow("textDocument/publishDiagnostics"));
+        // requests and responses are never filtered
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            notifications(&messages),
+            ["textDocument/publishDiagnostics"]
+        );
+
+        let filter = NotificationFilter::new()
+            .allow("window/*")
+            .deny("window/logMessage");
+        assert!(filter.permits("window/showMessage"));
+        assert!(!filter.permits("window/logMessage"));
+        assert!(!filter.permits("$/progress"));
+    }
+
+    #[test]
+    fn test_header_line_limit() {
+        let mut input = b"X-Padding: ".to_vec();
+        input.resize(100 * 1024, b'a');
+        let err = read_msg_text(&mut input.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("65536 bytes"), "{err}");
+
+        let opts = ReadOptions {
+            max_header_bytes: Some(16),
+            ..Default::default()
+        };
+        let input = frame(r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        let err = Message::read_with_options(&mut input.as_slice(), &opts).unwrap_err();
+        assert!(err.to_string().contains("16 bytes"), "{err}");
+        let opts = ReadOptions {
+            max_header_bytes: Some(20),
+            ..Default::default()
+        };
+        assert!(Message::read_with_options(&mut input.as_slice(), &opts)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_min_bytecode_bytes() {
+        let options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                min_bytecode_bytes: Some(128),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let write = |msg: Message| {
+            let mut buf = Vec::new();
+            msg.write_with_options(&mut buf, &options).unwrap();
+            read_msg_text(&mut buf.as_slice()).unwrap().unwrap()
+        };
+
+        let exit = Notification::new("exit".to_string(), ());
+        let text = write(exit.into());
+        assert!(text.starts_with('{'), "{text}");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()["method"],
+            "exit"
+        );
+        let resp = Response::new_ok(RequestId::from(1), ());
+        assert!(write(resp.into()).starts_with('{'));
+
+        let large = Notification::new("test/large".to_string(), vec![1; 100]);
+        assert!(write(large.into()).starts_with("#["));
+    }
+
+    #[test]
+    fn test_write_matches_reference_encoding() {
+        // what `_write` did before the message was serialized only once
+        fn reference(msg: &Message, options: &bytecode::BytecodeOptions) -> String {
+            let json_val = serde_json::to_value(msg.clone()).unwrap();
+            let mut json_val = json_val;
+            json_val["jsonrpc"] = "2.0".into();
+            match bytecode::generate_bytecode_repl(&json_val, options.clone()) {
+                Ok(bytecode) => bytecode,
+                Err(_) => serde_json::to_string(&json_val).unwrap(),
+            }
+        }
+
+        let messages: Vec<Message> = vec![
+            Notification::new("exit".to_string(), ()).into(),
+            Request::new(
+                RequestId::from(3),
+                "textDocument/hover".to_string(),
+                serde_json::json!({"position": {"line": 1, "character": 2}}),
+            )
+            .into(),
+            Response::new_ok(RequestId::from(3), vec!["a"; 50]).into(),
+            Response::new_err(
+                RequestId::from(4),
+                ErrorCode::InvalidParams,
+                "bad".to_string(),
+            )
+            .into(),
+        ];
+        for max_bytecode_bytes in [None, Some(10)] {
+            let options = WriteOptions {
+                bytecode: bytecode::BytecodeOptions {
+                    max_bytecode_bytes,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            for msg in &messages {
+                let mut buf = Vec::new();
+                msg.clone().write_with_options(&mut buf, &options).unwrap();
+                let body = read_msg_text(&mut buf.as_slice()).unwrap().unwrap();
+                assert_eq!(body, reference(msg, &options.bytecode));
+            }
         }
     }
 
-    let size: usize = size.ok_or_else(|| invalid_data!("no Content-Length"))?;
-    let mut buf = buf.into_bytes();
-    buf.resize(size, 0);
-    inp.read_exact(&mut buf)?;
-    let buf = String::from_utf8(buf).map_err(invalid_data)?;
-    debug!("< {}", buf);
-    Ok(Some(buf))
-}
+    #[test]
+    fn test_read_write_raw() {
+        let request = frame(
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"uri":"file:///a.rs"}}"#,
+        );
+        let response = b"Content-Length: 38\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}";
+        let mut input = request.clone();
+        input.extend_from_slice(response);
 
-fn write_msg_text(out: &mut dyn Write, msg: &str) -> io::Result<()> {
-    // debug!("> {}", msg);
-    write!(out, "Content-Length: {}\r\n\r\n", msg.len())?;
-    out.write_all(msg.as_bytes())?;
-    out.flush()?;
-    Ok(())
-}
+        let mut inp = input.as_slice();
+        let mut out = Vec::new();
+        let (method, raw) = Message::read_raw(&mut inp).unwrap().unwrap();
+        assert_eq!(method, "textDocument/hover");
+        Message::write_raw(&mut out, &raw).unwrap();
+        let (method, raw) = Message::read_raw(&mut inp).unwrap().unwrap();
+        assert_eq!(method, "");
+        Message::write_raw(&mut out, &raw).unwrap();
+        assert!(Message::read_raw(&mut inp).unwrap().is_none());
+        assert_eq!(out, input);
 
-#[derive(Clone, Copy, Debug)]
-#[non_exhaustive]
-#[allow(dead_code)]
-pub enum ErrorCode {
-    // Defined by JSON RPC:
-    ParseError = -32700,
-    InvalidRequest = -32600,
-    MethodNotFound = -32601,
-    InvalidParams = -32602,
-    InternalError = -32603,
-    ServerErrorStart = -32099,
-    ServerErrorEnd = -32000,
+        // chunks are forwarded as one frame
+        let mut chunked = Vec::new();
+        let not = Notification::new("test/chunked".to_string(), vec![1; 100]);
+        Message::from(not).write_chunked(&mut chunked, 64).unwrap();
+        let (method, raw) = Message::read_raw(&mut chunked.as_slice()).unwrap().unwrap();
+        assert_eq!(method, "test/chunked");
+        let mut out = Vec::new();
+        Message::write_raw(&mut out, &raw).unwrap();
+        let text = read_msg_text(&mut out.as_slice()).unwrap().unwrap();
+        assert_eq!(text.as_bytes(), raw.body());
+    }
 
-    /// Error code indicating that a server received a notification or
-    /// request before the server has received the `initialize` request.
-    ServerNotInitialized = -32002,
-    UnknownErrorCode = -32001,
+    #[test]
+    fn test_emit_jsonrpc_field() {
+        let write = |emit_jsonrpc_field| {
+            let options = WriteOptions {
+                emit_jsonrpc_field,
+                bytecode: bytecode::BytecodeOptions {
+                    max_bytecode_bytes: Some(0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            Message::from(Notification::new("exit".to_string(), ()))
+                .write_with_options(&mut buf, &options)
+                .unwrap();
+            let text = read_msg_text(&mut buf.as_slice()).unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        assert_eq!(write(true)["jsonrpc"], "2.0");
+        let without = write(false);
+        assert!(without.get("jsonrpc").is_none());
+        assert_eq!(without["method"], "exit");
 
-    // Defined by the protocol:
-    /// The client has canceled a request and a server has detected
-    /// the cancel.
-    RequestCanceled = -32800,
+        // the reader accepts it back
+        let mut buf = Vec::new();
+        let options = WriteOptions {
+            emit_jsonrpc_field: false,
+            ..Default::default()
+        };
+        Message::from(Response::new_ok(RequestId::from(1), ()))
+            .write_with_options(&mut buf, &options)
+            .unwrap();
+        assert!(matches!(
+            read_written(&mut buf.as_slice()).unwrap(),
+            Some(Message::Response(_))
+        ));
+    }
 
-    /// The server detected that the content of a document got
-    /// modified outside normal conditions. A server should
-    /// NOT send this error code if it detects a content change
-    /// in it unprocessed messages. The result even computed
-    /// on an older state might still be useful for the client.
-    ///
-    /// If a client decides that a result is not of any use anymore
-    /// the client should cancel the request.
-    ContentModified = -32801,
+    #[test]
+    fn test_crlf_in_body() {
+        let text = "line one\r\nline two\r\n\r\nContent-Length: 3\r\n";
+        let not = Notification::new("test/crlf".to_string(), serde_json::json!({"text": text}));
+        for max_bytecode_bytes in [None, Some(0)] {
+            let options = WriteOptions {
+                bytecode: bytecode::BytecodeOptions {
+                    max_bytecode_bytes,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            Message::from(not.clone())
+                .write_with_options(&mut buf, &options)
+                .unwrap();
+            Message::from(not.clone())
+                .write_with_options(&mut buf, &options)
+                .unwrap();
+            let mut inp = buf.as_slice();
+            for _ in 0..2 {
+                let Some(Message::Notification(read)) = read_written(&mut inp).unwrap() else {
+                    panic!("expected a notification");
+                };
+                assert_eq!(read.params.params["text"], text);
+            }
+            assert!(Message::read(&mut inp).unwrap().is_none());
+        }
 
-    /// The server cancelled the request. This error code should
-    /// only be used for requests that explicitly support being
-    /// server cancellable.
-    ///
-    /// @since 3.17.0
-    ServerCancelled = -32802,
+        // CRLFs between the JSON tokens are part of the body too
+        let body = "{\r\n\"jsonrpc\": \"2.0\",\r\n\"method\": \"exit\"\r\n}\r\n";
+        let mut input = frame(body);
+        input.extend(frame(body));
+        let mut inp = input.as_slice();
+        assert_eq!(read_msg_text(&mut inp).unwrap().unwrap(), body);
+        assert_eq!(read_msg_text(&mut inp).unwrap().unwrap(), body);
+    }
 
-    /// A request failed but it was syntactically correct, e.g the
-    /// method name was known and the parameters were valid. The error
-    /// message should contain human readable information about why
-    /// the request failed.
-    ///
-    /// @since 3.17.0
-    RequestFailed = -32803,
+    #[test]
+    fn test_validate() {
+        let parse = |text: &str| Message::parse_text(text, None, &ReadOptions::default()).unwrap();
+
+        let both =
+            parse(r#"{"jsonrpc":"2.0","id":1,"result":null,"error":{"code":1,"message":"x"}}"#);
+        assert_eq!(
+            both.validate(),
+            Err(ValidationError::ResultAndError(RequestId::from(1)))
+        );
+        let neither = parse(r#"{"jsonrpc":"2.0","id":2}"#);
+        assert_eq!(
+            neither.validate(),
+            Err(ValidationError::NoResultOrError(RequestId::from(2)))
+        );
+        let reserved = parse(r#"{"jsonrpc":"2.0","method":"rpc.discover","params":{}}"#);
+        assert!(matches!(
+            reserved.validate(),
+            Err(ValidationError::ReservedMethod(_))
+        ));
+        assert_eq!(
+            parse(r#"{"jsonrpc":"2.0","id":3,"result":null}"#).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            parse(r#"{"jsonrpc":"2.0","id":4,"method":"shutdown","params":{}}"#).validate(),
+            Ok(())
+        );
+
+        // only checked on read when asked for
+        let input = frame(r#"{"jsonrpc":"2.0","id":2}"#);
+        assert!(Message::read(&mut input.as_slice()).unwrap().is_some());
+        let opts = ReadOptions {
+            validate: true,
+            ..Default::default()
+        };
+        let err = Message::read_with_options(&mut input.as_slice(), &opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("neither"), "{err}");
+    }
+
+    #[test]
+    fn test_write_from_reader() {
+        let mut body = b"[".to_vec();
+        let mut count = 0;
+        while body.len() < 1024 * 1024 {
+            body.extend_from_slice(br#"{"name":"symbol","kind":12},"#);
+            count += 1;
+        }
+        body.extend_from_slice(br#"{"name":"last","kind":12}]"#);
+
+        let mut out = Vec::new();
+        Message::write_from_reader(
+            Some(RequestId::from(9)),
+            None,
+            body.as_slice(),
+            body.len(),
+            &mut out,
+        )
+        .unwrap();
+        Message::write_from_reader(
+            None,
+            Some("test/streamed"),
+            br#"{"uri":"file:///a.rs"}"#.as_slice(),
+            22,
+            &mut out,
+        )
+        .unwrap();
+
+        let mut inp = out.as_slice();
+        let Some(Message::Response(resp)) = Message::read(&mut inp).unwrap() else {
+            panic!("expected a response");
+        };
+        assert_eq!(resp.id, RequestId::from(9));
+        let symbols = resp.result.unwrap();
+        assert_eq!(symbols.as_array().unwrap().len(), count + 1);
+        assert_eq!(symbols[count]["name"], "last");
+        let Some(Message::Notification(not)) = Message::read(&mut inp).unwrap() else {
+            panic!("expected a notification");
+        };
+        assert_eq!(not.method, "test/streamed");
+        assert_eq!(not.uri().as_deref(), Some("file:///a.rs"));
+
+        let err = Message::write_from_reader(
+            Some(RequestId::from(1)),
+            None,
+            b"[1]".as_slice(),
+            10,
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_with_raw() {
+        // out of order keys and insignificant whitespace
+        let body = r#"{ "params": {"b": 1, "a": 2}, "method": "test/raw", "jsonrpc": "2.0" }"#;
+        let input = frame(body);
+        let (msg, raw) = Message::read_with_raw(&mut input.as_slice())
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw, body);
+        assert_eq!(msg.method(), Some("test/raw"));
+
+        let mut written = Vec::new();
+        let options = WriteOptions {
+            bytecode: bytecode::BytecodeOptions {
+                max_bytecode_bytes: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        msg.write_with_options(&mut written, &options).unwrap();
+        assert_ne!(
+            read_msg_text(&mut written.as_slice()).unwrap().unwrap(),
+            raw
+        );
+
+        assert!(Message::read_with_raw(&mut [].as_slice())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_content_type_charset() {
+        let body = r#"{"jsonrpc": "2.0", "method": "exit", "params": {}}"#;
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let text = read_msg_text(&mut input.as_bytes()).unwrap().unwrap();
+        assert_eq!(text, body);
+
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=latin1\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let err = read_msg_text(&mut input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("latin1"));
+    }
 }
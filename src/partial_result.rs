@@ -0,0 +1,121 @@
+//! Partial results streamed through `$/progress`, for requests such as
+//! `workspace/symbol` that were sent with a `partialResultToken`.
+
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::msg::{ProgressParams, ProgressToken, RequestId, Response};
+
+/// Collects the partial result arrays of pending requests, keyed by their
+/// partial result token, and merges them into the final response.
+#[derive(Debug, Default)]
+pub struct PartialResultAccumulator {
+    pending: HashMap<ProgressToken, (RequestId, Vec<Value>)>,
+}
+
+impl PartialResultAccumulator {
+    pub fn new() -> PartialResultAccumulator {
+        PartialResultAccumulator::default()
+    }
+
+    /// Collect partial results sent with `token` for the request `id`.
+    pub fn register(&mut self, token: ProgressToken, id: RequestId) {
+        self.pending.insert(token, (id, Vec::new()));
+    }
+
+    /// Take the items of a `$/progress` notification. Returns `false` if
+    /// the token is unknown or the value is not an array, in which case the
+    /// notification should be handled as ordinary progress.
+    pub fn push(&mut self, params: ProgressParams) -> bool {
+        let Some((_, items)) = self.pending.get_mut(&params.token) else {
+            return false;
+        };
+        match params.value {
+            Value::Array(chunk) => {
+                items.extend(chunk);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Merge the collected items into the final response of their request.
+    /// The final result comes after the partial ones; an error response is
+    /// returned unchanged.
+    pub fn complete(&mut self, mut resp: Response) -> Response {
+        let Some(token) = self
+            .pending
+            .iter()
+            .find(|(_, (id, _))| *id == resp.id)
+            .map(|(token, _)| token.clone())
+        else {
+            return resp;
+        };
+        let (_, mut items) = self.pending.remove(&token).unwrap();
+        if resp.error.is_some() {
+            return resp;
+        }
+        match resp.result.take() {
+            None | Some(Value::Null) => {}
+            Some(Value::Array(rest)) => items.extend(rest),
+            Some(result) => {
+                log::warn!("partial results for {} with a non-array result", resp.id);
+                resp.result = Some(result);
+                return resp;
+            }
+        }
+        resp.result = Some(Value::Array(items));
+        resp
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(token: &str, value: Value) -> ProgressParams {
+        ProgressParams {
+            token: ProgressToken::from(token.to_string()),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_merge_partial_results() {
+        let mut acc = PartialResultAccumulator::new();
+        acc.register(
+            ProgressToken::from("symbols".to_string()),
+            RequestId::from(7),
+        );
+
+        assert!(acc.push(chunk("symbols", json!([{"name": "a"}, {"name": "b"}]))));
+        assert!(acc.push(chunk("symbols", json!([{"name": "c"}]))));
+        // ordinary work done progress is left alone
+        assert!(!acc.push(chunk("symbols", json!({"kind": "report"}))));
+        assert!(!acc.push(chunk("other", json!([{"name": "x"}]))));
+
+        // unrelated responses pass through
+        let other = acc.complete(Response::new_ok(RequestId::from(8), json!(null)));
+        assert_eq!(other.result, Some(json!(null)));
+
+        let resp = acc.complete(Response::new_ok(RequestId::from(7), json!([])));
+        assert_eq!(
+            resp.result,
+            Some(json!([{"name": "a"}, {"name": "b"}, {"name": "c"}]))
+        );
+        assert!(acc.is_empty());
+    }
+}
@@ -1,8 +1,18 @@
-use std::collections::HashMap;
+// Not all of the queue is used by the main loop yet.
+#![allow(dead_code)]
 
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use parking_lot::Mutex;
 use serde::Serialize;
 
-use crate::msg::{ErrorCode, Request, RequestId, Response, ResponseError};
+use crate::msg::{
+    ErrorCode, IdAllocator, Message, Notification, Request, RequestId, Response, ResponseError,
+};
 
 #[derive(Debug)]
 pub struct ReqQueue<I, O> {
@@ -87,3 +97,517 @@ impl<O> Outgoing<O> {
         self.pending.remove(&id)
     }
 }
+
+/// Correlates responses with the requests that were written, so the method and
+/// latency of a request can be recovered when its response arrives.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    /// The method, uri and send time of each request.
+    pending: HashMap<RequestId, (String, Option<String>, Instant)>,
+    max_pending: Option<usize>,
+}
+
+impl PendingRequests {
+    /// Reject requests once `max_pending` are waiting for a response.
+    pub fn with_max_pending(max_pending: usize) -> PendingRequests {
+        PendingRequests {
+            pending: HashMap::new(),
+            max_pending: Some(max_pending),
+        }
+    }
+
+    /// Record a request about to be forwarded. Over the limit, the request
+    /// must not be forwarded; the error response to send back is returned.
+    pub fn record(&mut self, request: &Request) -> Result<(), Response> {
+        if self
+            .max_pending
+            .is_some_and(|max| self.pending.len() >= max)
+        {
+            warn!(
+                "rejecting {} ({}), {} requests are pending",
+                request.method,
+                request.id,
+                self.pending.len()
+            );
+            return Err(Response::new_err(
+                request.id.clone(),
+                ErrorCode::RequestFailed,
+                "too many pending requests".to_string(),
+            ));
+        }
+        self.pending.insert(
+            request.id.clone(),
+            (request.method.clone(), request.uri(), Instant::now()),
+        );
+        Ok(())
+    }
+
+    /// Returns the method of the request and the time elapsed since it was recorded.
+    pub fn take(&mut self, id: &RequestId) -> Option<(String, Duration)> {
+        match self.pending.remove(id) {
+            Some((method, _, sent_at)) => Some((method, sent_at.elapsed())),
+            None => {
+                warn!(
+                    "received orphan response({:?}) without a pending request",
+                    id
+                );
+                None
+            }
+        }
+    }
+
+    /// Removes all pending requests, returns their ids and methods ordered
+    /// by id.
+    pub fn drain(&mut self) -> Vec<(RequestId, String)> {
+        let mut drained: Vec<(RequestId, String)> = self
+            .pending
+            .drain()
+            .map(|(id, (method, _, _))| (id, method))
+            .collect();
+        drained.sort_by(|a, b| a.0.numeric_then_lexical_cmp(&b.0));
+        drained
+    }
+
+    /// Removes the requests for `uri`, e.g. when its buffer is closed, and
+    /// returns their ids ordered, each with the `$/cancelRequest` to send.
+    pub fn cancel_by_uri(&mut self, uri: &str) -> Vec<(RequestId, Notification)> {
+        let mut canceled: Vec<RequestId> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, request_uri, _))| request_uri.as_deref() == Some(uri))
+            .map(|(id, _)| id.clone())
+            .collect();
+        canceled.sort_by(|a, b| a.numeric_then_lexical_cmp(b));
+        canceled
+            .into_iter()
+            .map(|id| {
+                self.pending.remove(&id);
+                let cancel = Notification::cancel(id.clone());
+                (id, cancel)
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Gives up on the pending requests once the server has been silent for
+/// `idle_timeout`, so the client is not left waiting forever.
+#[derive(Debug)]
+pub struct IdleMonitor {
+    idle_timeout: Duration,
+    last_activity: Instant,
+}
+
+impl IdleMonitor {
+    pub fn new(idle_timeout: Duration, now: Instant) -> IdleMonitor {
+        IdleMonitor {
+            idle_timeout,
+            last_activity: now,
+        }
+    }
+
+    /// Call for every message received from the server.
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    pub fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_activity) >= self.idle_timeout
+    }
+
+    /// If the server is idle with requests pending, removes them and returns
+    /// an error response for each and a `window/showMessage` telling the user.
+    pub fn poll(&mut self, now: Instant, pending: &mut PendingRequests) -> Vec<Message> {
+        if pending.is_empty() || !self.is_idle(now) {
+            return Vec::new();
+        }
+        let message = format!(
+            "language server sent nothing for {:?}",
+            now.duration_since(self.last_activity)
+        );
+        let mut messages: Vec<Message> = pending
+            .drain()
+            .into_iter()
+            .map(|(id, method)| {
+                warn!("giving up on {} ({}): {}", method, id, message);
+                Response::new_err(id, ErrorCode::RequestFailed, message.clone()).into()
+            })
+            .collect();
+        messages.push(
+            Notification::from_lsp::<lsp_types::notification::ShowMessage>(
+                lsp_types::ShowMessageParams {
+                    typ: lsp_types::MessageType::WARNING,
+                    message: format!("{}, {} requests failed", message, messages.len()),
+                },
+            )
+            .into(),
+        );
+        messages
+    }
+}
+
+/// Ids of requests the client canceled, so late responses to them can be
+/// dropped instead of forwarded. An id is forgotten once it is checked.
+#[derive(Debug, Default)]
+pub struct CancellationRegistry {
+    canceled: Mutex<HashSet<RequestId>>,
+}
+
+impl CancellationRegistry {
+    pub fn cancel(&self, id: RequestId) {
+        self.canceled.lock().insert(id);
+    }
+
+    /// Record the id of a `$/cancelRequest` notification, returns whether it
+    /// was one.
+    pub fn record(&self, not: &Notification) -> bool {
+        match not.canceled_id() {
+            Some(id) => {
+                self.cancel(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `id` was canceled, forgetting it if so.
+    pub fn take(&self, id: &RequestId) -> bool {
+        self.canceled.lock().remove(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.canceled.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.canceled.lock().is_empty()
+    }
+}
+
+/// When a proxied request is given up on.
+#[derive(Debug, Clone)]
+pub struct RequestDeadline {
+    pub method: String,
+    pub deadline: Instant,
+}
+
+impl RequestDeadline {
+    pub fn new(request: &Request, timeout: Duration, now: Instant) -> RequestDeadline {
+        RequestDeadline {
+            method: request.method.clone(),
+            deadline: now + timeout,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// Deadlines of requests sent to the server, so the proxy can answer the
+/// client itself when the server never responds.
+#[derive(Debug, Default)]
+pub struct DeadlineRegistry {
+    deadlines: HashMap<RequestId, RequestDeadline>,
+}
+
+impl DeadlineRegistry {
+    pub fn register(&mut self, request: &Request, timeout: Duration, now: Instant) {
+        self.deadlines.insert(
+            request.id.clone(),
+            RequestDeadline::new(request, timeout, now),
+        );
+    }
+
+    /// Forget `id` once its response arrived.
+    pub fn complete(&mut self, id: &RequestId) -> Option<RequestDeadline> {
+        self.deadlines.remove(id)
+    }
+
+    /// Removes and returns the ids whose deadline passed by `now`, earliest first.
+    pub fn expired(&mut self, now: Instant) -> Vec<RequestId> {
+        let mut expired: Vec<(RequestId, Instant)> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| deadline.is_expired(now))
+            .map(|(id, deadline)| (id.clone(), deadline.deadline))
+            .collect();
+        expired.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        expired
+            .into_iter()
+            .map(|(id, _)| {
+                self.deadlines.remove(&id);
+                id
+            })
+            .collect()
+    }
+
+    /// The error response sent to the client for a timed out request.
+    pub fn timeout_response(id: RequestId) -> Response {
+        Response::new_err(
+            id,
+            ErrorCode::RequestFailed,
+            "request timed out".to_string(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+}
+
+/// A client connection of the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(pub usize);
+
+/// Routes the server's responses back to the client that sent the request.
+///
+/// Clients pick their request ids independently, so requests are forwarded
+/// with ids allocated by the table and the response gets the client's id
+/// back.
+#[derive(Debug)]
+pub struct RouteTable {
+    ids: IdAllocator,
+    /// Keyed by the forwarded id.
+    routes: HashMap<RequestId, (ClientId, RequestId)>,
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        RouteTable {
+            ids: IdAllocator::new(0),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl RouteTable {
+    /// Record a request from `client`, returns the id to forward it with.
+    pub fn register(&mut self, id: RequestId, client: ClientId) -> RequestId {
+        // after wrapping around, skip ids still waiting for a response
+        let forwarded = loop {
+            let forwarded = self.ids.next_id();
+            if !self.routes.contains_key(&forwarded) {
+                break forwarded;
+            }
+        };
+        self.routes.insert(forwarded.clone(), (client, id));
+        forwarded
+    }
+
+    /// The client waiting for the response to the forwarded `id`.
+    pub fn route(&self, id: &RequestId) -> Option<ClientId> {
+        self.routes.get(id).map(|(client, _)| *client)
+    }
+
+    /// Forget the route of `resp` and restore the client's request id.
+    pub fn complete(&mut self, mut resp: Response) -> Option<(ClientId, Response)> {
+        let (client, id) = self.routes.remove(&resp.id)?;
+        resp.id = id;
+        Some((client, resp))
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pending_requests() {
+        let mut pending = PendingRequests::default();
+        let request = Request::new(RequestId::from(1), "textDocument/hover".to_string(), ());
+        pending.record(&request).unwrap();
+        assert_eq!(pending.len(), 1);
+
+        let response = Response::new_ok(RequestId::from(1), ());
+        let (method, _latency) = pending.take(&response.id).unwrap();
+        assert_eq!(method, "textDocument/hover");
+        assert!(pending.is_empty());
+
+        assert!(pending.take(&RequestId::from(2)).is_none());
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut pending = PendingRequests::with_max_pending(2);
+        let request = |id| Request::new(RequestId::from(id), "textDocument/hover".to_string(), ());
+        pending.record(&request(1)).unwrap();
+        pending.record(&request(2)).unwrap();
+
+        let resp = pending.record(&request(3)).unwrap_err();
+        assert_eq!(resp.id, RequestId::from(3));
+        let error = resp.error.unwrap();
+        assert_eq!(error.code, ErrorCode::RequestFailed as i32);
+        assert_eq!(error.message, "too many pending requests");
+        assert_eq!(pending.len(), 2);
+
+        // room again once a response arrived
+        pending.take(&RequestId::from(1)).unwrap();
+        pending.record(&request(3)).unwrap();
+    }
+
+    #[test]
+    fn test_cancel_by_uri() {
+        let mut pending = PendingRequests::default();
+        for (id, uri) in [
+            (3, "file:///a.rs"),
+            (1, "file:///a.rs"),
+            (2, "file:///b.rs"),
+        ] {
+            pending
+                .record(&Request::new_with(
+                    RequestId::from(id),
+                    "textDocument/hover".to_string(),
+                    Some(uri.to_string()),
+                    None,
+                    (),
+                ))
+                .unwrap();
+        }
+
+        let canceled = pending.cancel_by_uri("file:///a.rs");
+        let ids: Vec<RequestId> = canceled.iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(ids, [RequestId::from(1), RequestId::from(3)]);
+        for (id, cancel) in &canceled {
+            assert_eq!(cancel.canceled_id().as_ref(), Some(id));
+        }
+
+        assert_eq!(pending.len(), 1);
+        assert!(pending.take(&RequestId::from(2)).is_some());
+        assert!(pending.cancel_by_uri("file:///a.rs").is_empty());
+    }
+
+    #[test]
+    fn test_cancellation_registry() {
+        let registry = CancellationRegistry::default();
+        assert!(registry.record(&Notification::cancel(RequestId::from(5))));
+        assert!(!registry.record(&Notification::new("exit".to_string(), ())));
+        assert_eq!(registry.len(), 1);
+
+        let response = Response::new_ok(RequestId::from(5), ());
+        assert!(response.is_for_canceled(&registry));
+        // the id expires once checked
+        assert!(!response.is_for_canceled(&registry));
+        assert!(registry.is_empty());
+
+        assert!(!Response::new_ok(RequestId::from(6), ()).is_for_canceled(&registry));
+    }
+
+    #[test]
+    fn test_deadline_registry() {
+        let mut registry = DeadlineRegistry::default();
+        let now = Instant::now();
+        let timeout = Duration::from_millis(10);
+        let slow = Request::new(RequestId::from(1), "textDocument/hover".to_string(), ());
+        let fast = Request::new(RequestId::from(2), "textDocument/hover".to_string(), ());
+        registry.register(&slow, timeout, now);
+        registry.register(&fast, timeout, now);
+        assert!(registry.complete(&fast.id).is_some());
+
+        assert!(registry.expired(now).is_empty());
+        assert_eq!(registry.expired(now + timeout), vec![RequestId::from(1)]);
+        assert!(registry.is_empty());
+
+        let response = DeadlineRegistry::timeout_response(RequestId::from(1));
+        assert_eq!(
+            response.error.unwrap().error_code(),
+            Some(ErrorCode::RequestFailed)
+        );
+    }
+
+    #[test]
+    fn test_route_table() {
+        let mut routes = RouteTable::default();
+        let (a, b) = (ClientId(0), ClientId(1));
+        // both clients use id 1
+        let from_a = routes.register(RequestId::from(1), a);
+        let from_b = routes.register(RequestId::from(1), b);
+        assert_ne!(from_a, from_b);
+        assert_eq!(routes.route(&from_a), Some(a));
+        assert_eq!(routes.route(&from_b), Some(b));
+
+        let (client, resp) = routes
+            .complete(Response::new_ok(from_b.clone(), ()))
+            .unwrap();
+        assert_eq!((client, resp.id), (b, RequestId::from(1)));
+        assert_eq!(routes.route(&from_b), None);
+        assert!(routes.complete(Response::new_ok(from_b, ())).is_none());
+        assert_eq!(routes.len(), 1);
+    }
+
+    #[test]
+    fn test_route_table_wrap_around() {
+        let mut routes = RouteTable {
+            ids: IdAllocator::new(i32::MAX - 1),
+            routes: HashMap::new(),
+        };
+        let first = routes.register(RequestId::from(1), ClientId(0));
+        let second = routes.register(RequestId::from(2), ClientId(0));
+        assert_eq!(second, RequestId::from(i32::MAX));
+        routes.complete(Response::new_ok(second, ())).unwrap();
+
+        // the allocator wraps to `first`, which is still pending
+        let third = routes.register(RequestId::from(3), ClientId(1));
+        assert_eq!(third, RequestId::from(i32::MAX));
+        assert_eq!(routes.route(&first), Some(ClientId(0)));
+        assert_eq!(routes.route(&third), Some(ClientId(1)));
+    }
+
+    #[test]
+    fn test_idle_monitor() {
+        let start = Instant::now();
+        let mut monitor = IdleMonitor::new(Duration::from_secs(10), start);
+        let mut pending = PendingRequests::default();
+        // idle without pending requests is fine
+        assert!(monitor
+            .poll(start + Duration::from_secs(20), &mut pending)
+            .is_empty());
+
+        monitor.record_activity(start + Duration::from_secs(20));
+        for (id, method) in [(2, "textDocument/hover"), (1, "textDocument/definition")] {
+            pending
+                .record(&Request::new(RequestId::from(id), method.to_string(), ()))
+                .unwrap();
+        }
+        assert!(monitor
+            .poll(start + Duration::from_secs(29), &mut pending)
+            .is_empty());
+
+        let messages = monitor.poll(start + Duration::from_secs(30), &mut pending);
+        assert!(pending.is_empty());
+        assert_eq!(messages.len(), 3);
+        for (msg, id) in messages.iter().zip([1, 2]) {
+            let resp = msg.as_response().unwrap();
+            assert_eq!(resp.id, RequestId::from(id));
+            assert_eq!(
+                resp.error.as_ref().unwrap().code,
+                ErrorCode::RequestFailed as i32
+            );
+        }
+        let not = messages[2].as_notification().unwrap();
+        assert_eq!(not.method, "window/showMessage");
+        assert!(not.params.params["message"]
+            .as_str()
+            .unwrap()
+            .contains("2 requests failed"));
+    }
+}
@@ -0,0 +1,248 @@
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::msg::{Message, Notification};
+
+/// The `telemetry/event` reporting replaced notifications.
+pub const COALESCED_EVENT: &str = "lsp-copilot/coalesced-notifications";
+
+/// A budget per second of writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteLimit {
+    Messages(u64),
+    Bytes(u64),
+}
+
+/// Limits the rate of notifications written to `inner`, e.g. bursts of
+/// diagnostics that would overwhelm Emacs.
+///
+/// Requests and responses are always written right away. Notifications over
+/// the budget wait in a queue, where a newer notification with the same
+/// method and uri replaces the older one. Call `flush_pending` to write
+/// queued notifications once the budget allows.
+///
+/// Each window in which notifications were replaced is followed by a
+/// `telemetry/event` with their count, queued like any other notification.
+pub struct ThrottledWriter<W: Write> {
+    inner: W,
+    limit: WriteLimit,
+    window_start: Option<Instant>,
+    used: u64,
+    pending: VecDeque<Notification>,
+    /// Notifications replaced in the current window.
+    coalesced: u64,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    pub fn new(inner: W, limit: WriteLimit) -> ThrottledWriter<W> {
+        ThrottledWriter {
+            inner,
+            limit,
+            window_start: None,
+            used: 0,
+            pending: VecDeque::new(),
+            coalesced: 0,
+        }
+    }
+
+    pub fn write(&mut self, msg: Message, now: Instant) -> io::Result<()> {
+        match msg {
+            Message::Notification(not) => {
+                self.enqueue(not);
+                self.flush_pending(now)?;
+                Ok(())
+            }
+            msg => {
+                self.roll_window(now);
+                let buf = encode(msg)?;
+                self.used += self.cost(&buf);
+                self.inner.write_all(&buf)?;
+                self.inner.flush()
+            }
+        }
+    }
+
+    /// Write queued notifications while the budget allows, returns how many
+    /// were written.
+    pub fn flush_pending(&mut self, now: Instant) -> io::Result<usize> {
+        self.roll_window(now);
+        let mut written = 0;
+        while let Some(not) = self.pending.front() {
+            let buf = encode(not.clone().into())?;
+            let cost = self.cost(&buf);
+            // a message larger than the whole budget still goes out alone
+            if self.used > 0 && self.used + cost > self.limit() {
+                break;
+            }
+            self.pending.pop_front();
+            self.used += cost;
+            self.inner.write_all(&buf)?;
+            written += 1;
+        }
+        if written > 0 {
+            self.inner.flush()?;
+        }
+        Ok(written)
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn enqueue(&mut self, not: Notification) {
+        if let Some(key) = coalesce_key(&not) {
+            let older = self
+                .pending
+                .iter_mut()
+                .find(|older| coalesce_key(older).as_ref() == Some(&key));
+            if let Some(older) = older {
+                *older = not;
+                self.coalesced += 1;
+                return;
+            }
+        }
+        self.pending.push_back(not);
+    }
+
+    fn roll_window(&mut self, now: Instant) {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.used = 0;
+                if self.coalesced > 0 {
+                    let count = std::mem::take(&mut self.coalesced);
+                    self.pending.push_back(Notification::telemetry(
+                        COALESCED_EVENT,
+                        serde_json::json!({ "count": count }),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn limit(&self) -> u64 {
+        match self.limit {
+            WriteLimit::Messages(limit) | WriteLimit::Bytes(limit) => limit,
+        }
+    }
+
+    fn cost(&self, buf: &[u8]) -> u64 {
+        match self.limit {
+            WriteLimit::Messages(_) => 1,
+            WriteLimit::Bytes(_) => buf.len() as u64,
+        }
+    }
+}
+
+/// Notifications with the same method and uri supersede each other.
+/// Notifications without a uri are never coalesced.
+fn coalesce_key(not: &Notification) -> Option<(String, String)> {
+    let uri = not.uri().or_else(|| {
+        not.params
+            .params
+            .get("uri")
+            .and_then(|uri| uri.as_str())
+            .map(str::to_owned)
+    })?;
+    Some((not.method.clone(), uri))
+}
+
+fn encode(msg: Message) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    msg.write(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msg::{ReadOptions, RequestId, Response};
+
+    fn diagnostics(uri: &str, version: i32) -> Message {
+        Notification::new(
+            "textDocument/publishDiagnostics".to_string(),
+            serde_json::json!({"uri": uri, "version": version, "diagnostics": []}),
+        )
+        .into()
+    }
+
+    fn read_all(mut buf: &[u8]) -> Vec<Message> {
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..Default::default()
+        };
+        std::iter::from_fn(|| Message::read_with_options(&mut buf, &opts).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_throttled_writer() {
+        let mut writer = ThrottledWriter::new(Vec::new(), WriteLimit::Messages(10));
+        let now = Instant::now();
+        for i in 0..100 {
+            let uri = format!("file:///{}.rs", i % 20);
+            writer.write(diagnostics(&uri, i), now).unwrap();
+        }
+        // responses are never held back
+        let resp = Response::new_ok(RequestId::from(1), ());
+        writer.write(resp.into(), now).unwrap();
+        // ten delivered, each uri waits once with its latest version
+        assert_eq!(writer.pending_len(), 20);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(writer.flush_pending(later).unwrap(), 10);
+        // the rest and the telemetry for the last window
+        assert_eq!(writer.pending_len(), 11);
+        assert_eq!(
+            writer
+                .flush_pending(later + Duration::from_secs(1))
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            writer
+                .flush_pending(later + Duration::from_secs(2))
+                .unwrap(),
+            1
+        );
+
+        let messages = read_all(&writer.into_inner());
+        assert_eq!(messages.len(), 32);
+        assert!(matches!(messages[10], Message::Response(_)));
+        let Message::Notification(last) = &messages[20] else {
+            panic!("expected a notification");
+        };
+        assert_eq!(last.params.params["uri"], "file:///19.rs");
+        assert_eq!(last.params.params["version"], 99);
+        let Message::Notification(telemetry) = &messages[31] else {
+            panic!("expected a notification");
+        };
+        let event = telemetry.as_telemetry().unwrap();
+        assert_eq!(event.event, COALESCED_EVENT);
+        assert_eq!(event.data["count"], 70);
+    }
+
+    #[test]
+    fn test_throttled_writer_bytes() {
+        let size = encode(diagnostics("file:///a.rs", 0)).unwrap().len() as u64;
+        let mut writer = ThrottledWriter::new(Vec::new(), WriteLimit::Bytes(size * 3));
+        let now = Instant::now();
+        for i in 0..5 {
+            writer
+                .write(diagnostics(&format!("file:///{}.rs", i), 0), now)
+                .unwrap();
+        }
+        assert_eq!(writer.pending_len(), 2);
+        assert_eq!(read_all(&writer.into_inner()).len(), 3);
+    }
+}
@@ -0,0 +1,126 @@
+//! In-memory transport, for driving the read-handle-write loop in tests
+//! without OS pipes.
+
+// Not wired into the main loop yet.
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, Cursor, Read, Write};
+
+use crate::msg::{Message, ReadOptions};
+
+/// Two in-memory byte streams: what the proxy reads (filled by [`push`])
+/// and what it writes (drained by [`pop`]).
+///
+/// [`push`]: DuplexTransport::push
+/// [`pop`]: DuplexTransport::pop
+#[derive(Debug, Default)]
+pub struct DuplexTransport {
+    inbound: Cursor<Vec<u8>>,
+    outbound: Cursor<Vec<u8>>,
+}
+
+impl DuplexTransport {
+    pub fn new() -> DuplexTransport {
+        DuplexTransport::default()
+    }
+
+    /// Queue `msg` to be read from the transport, as JSON like the client
+    /// sends it.
+    pub fn push(&mut self, msg: Message) -> io::Result<()> {
+        compact(&mut self.inbound);
+        let body = serde_json::to_string(&msg)?;
+        write!(
+            self.inbound.get_mut(),
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    /// Take the next message written to the transport, `None` if there is
+    /// none yet. Bytecode is decoded.
+    pub fn pop(&mut self) -> io::Result<Option<Message>> {
+        let opts = ReadOptions {
+            accept_bytecode: true,
+            ..Default::default()
+        };
+        let msg = Message::read_with_options(&mut self.outbound, &opts);
+        compact(&mut self.outbound);
+        msg
+    }
+
+    /// The bytes written to the transport and not popped yet.
+    pub fn written(&self) -> &[u8] {
+        &self.outbound.get_ref()[self.outbound.position() as usize..]
+    }
+}
+
+/// Drop the consumed bytes once everything was read.
+fn compact(cursor: &mut Cursor<Vec<u8>>) {
+    if cursor.position() as usize == cursor.get_ref().len() {
+        cursor.get_mut().clear();
+        cursor.set_position(0);
+    }
+}
+
+impl Read for DuplexTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf)
+    }
+}
+
+impl BufRead for DuplexTransport {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inbound.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inbound.consume(amt)
+    }
+}
+
+impl Write for DuplexTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msg::{Request, RequestId};
+
+    #[test]
+    fn test_round_trip() {
+        let mut transport = DuplexTransport::new();
+        let req = Request::new(
+            RequestId::from(1),
+            "textDocument/hover".to_string(),
+            serde_json::json!({"position": {"line": 0, "character": 0}}),
+        );
+        transport.push(req.into()).unwrap();
+
+        // what a handler would do
+        let Some(Message::Request(req)) = Message::read(&mut transport).unwrap() else {
+            panic!("expected a request");
+        };
+        assert!(Message::read(&mut transport).unwrap().is_none());
+        Message::from(req.ok_response("hover text"))
+            .write(&mut transport)
+            .unwrap();
+
+        match transport.pop().unwrap() {
+            Some(Message::Response(resp)) => {
+                assert_eq!(resp.id, RequestId::from(1));
+                assert_eq!(resp.result, Some(serde_json::json!("hover text")));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(transport.pop().unwrap().is_none());
+        assert!(transport.written().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+//! Uri prefix mapping for language servers running in a container or on a
+//! remote host, where the paths differ from the ones Emacs sees.
+
+use serde_json::Value;
+
+/// Maps uris by prefix, e.g. `file:///home/me/proj` to `file:///workspace`.
+///
+/// A rewriter maps one way, client to server. Use [`UriRewriter::reversed`]
+/// for the messages going back to the client.
+#[derive(Debug, Clone, Default)]
+pub struct UriRewriter {
+    /// `(from, to)` prefixes, without trailing slashes.
+    rules: Vec<(String, String)>,
+}
+
+impl UriRewriter {
+    pub fn new() -> UriRewriter {
+        UriRewriter::default()
+    }
+
+    /// Map uris under `client_prefix` to the same path under `server_prefix`.
+    pub fn rule(mut self, client_prefix: &str, server_prefix: &str) -> UriRewriter {
+        self.rules.push((
+            client_prefix.trim_end_matches('/').to_string(),
+            server_prefix.trim_end_matches('/').to_string(),
+        ));
+        self
+    }
+
+    /// The rewriter for the other direction, server to client.
+    pub fn reversed(&self) -> UriRewriter {
+        UriRewriter {
+            rules: self
+                .rules
+                .iter()
+                .map(|(from, to)| (to.clone(), from.clone()))
+                .collect(),
+        }
+    }
+
+    /// Rewrite `uri` by the longest matching prefix. A prefix only matches
+    /// whole path segments, so `file:///proj` does not match `file:///project`.
+    pub fn rewrite(&self, uri: &str) -> Option<String> {
+        self.rules
+            .iter()
+            .filter(|(from, _)| {
+                uri.strip_prefix(from.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))
+            })
+            .max_by_key(|(from, _)| from.len())
+            .map(|(from, to)| format!("{}{}", to, &uri[from.len()..]))
+    }
+
+    /// Rewrite `uri` in place, returns whether it changed.
+    pub fn rewrite_in_place(&self, uri: &mut String) -> bool {
+        match self.rewrite(uri) {
+            Some(rewritten) => {
+                *uri = rewritten;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rewrite the uris in a JSON value: the string values of every `uri`
+    /// key and of keys ending in `Uri`, such as `targetUri` or `rootUri`, and
+    /// the keys of a `WorkspaceEdit`'s `changes`, which are uris too.
+    pub fn rewrite_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match value {
+                        Value::String(uri) if key == "uri" || key.ends_with("Uri") => {
+                            self.rewrite_in_place(uri);
+                        }
+                        Value::Object(changes) if key == "changes" => {
+                            *changes = std::mem::take(changes)
+                                .into_iter()
+                                .map(|(uri, mut edits)| {
+                                    self.rewrite_value(&mut edits);
+                                    (self.rewrite(&uri).unwrap_or(uri), edits)
+                                })
+                                .collect();
+                        }
+                        value => self.rewrite_value(value),
+                    }
+                }
+            }
+            Value::Array(values) => values
+                .iter_mut()
+                .for_each(|value| self.rewrite_value(value)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let rewriter = UriRewriter::new().rule("file:///home/me/proj/", "file:///workspace");
+        assert_eq!(
+            rewriter
+                .rewrite("file:///home/me/proj/src/main.rs")
+                .as_deref(),
+            Some("file:///workspace/src/main.rs")
+        );
+        assert_eq!(
+            rewriter.rewrite("file:///home/me/proj").as_deref(),
+            Some("file:///workspace")
+        );
+        assert_eq!(rewriter.rewrite("file:///home/me/project/a.rs"), None);
+
+        let back = rewriter.reversed();
+        assert_eq!(
+            back.rewrite("file:///workspace/src/main.rs").as_deref(),
+            Some("file:///home/me/proj/src/main.rs")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_value() {
+        let rewriter = UriRewriter::new().rule("file:///workspace", "file:///home/me/proj");
+        let mut value = serde_json::json!([{
+            "targetUri": "file:///workspace/a.rs",
+            "name": "file:///workspace/b.rs",
+        }]);
+        rewriter.rewrite_value(&mut value);
+        assert_eq!(value[0]["targetUri"], "file:///home/me/proj/a.rs");
+        assert_eq!(value[0]["name"], "file:///workspace/b.rs");
+    }
+
+    #[test]
+    fn test_rewrite_workspace_edit() {
+        let rewriter = UriRewriter::new().rule("file:///workspace", "file:///home/me/proj");
+        let edit = serde_json::json!([{"range": {}, "newText": "renamed"}]);
+        let mut value = serde_json::json!({
+            "changes": {
+                "file:///workspace/a.rs": edit,
+                "file:///elsewhere/b.rs": edit,
+            },
+            "documentChanges": [{
+                "textDocument": {"uri": "file:///workspace/c.rs", "version": 1},
+                "edits": edit,
+            }],
+        });
+        rewriter.rewrite_value(&mut value);
+        assert_eq!(
+            value["changes"],
+            serde_json::json!({
+                "file:///home/me/proj/a.rs": edit,
+                "file:///elsewhere/b.rs": edit,
+            })
+        );
+        assert_eq!(
+            value["documentChanges"][0]["textDocument"]["uri"],
+            "file:///home/me/proj/c.rs"
+        );
+    }
+}